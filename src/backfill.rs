@@ -0,0 +1,88 @@
+//! Counting messages from before the bot started, so an established server doesn't start from zero
+
+use std::sync::Arc;
+
+use ephemerole::Bot;
+use twilight_http::Client;
+use twilight_model::{
+    channel::Message,
+    id::{marker::ChannelMarker, Id},
+};
+
+use crate::{grant::Granter, settings::Settings};
+
+/// The most messages discord will give us at once
+const PAGE_SIZE: usize = 100;
+
+/// Count the recent history of every channel we've been told to look at, and give
+/// out roles to anyone who earned them. Most people who earn the role from old messages get it
+/// on their next one instead, see [`ephemerole::backfill`]. This runs before we start listening
+/// for new messages, so nothing we see live can be counted twice.
+///
+/// If backfilling isn't turned on, this does nothing.
+pub async fn run(client: &Arc<Client>, settings: &Settings, bot: &mut Bot, granter: &mut Granter) {
+    if !settings.backfill {
+        return;
+    }
+    let mut history = Vec::new();
+    for channel in &settings.backfill_channels {
+        fetch_channel(client, *channel, settings.backfill_limit, &mut history).await;
+    }
+    println!("Counting {} messages from before we started", history.len());
+    // Old messages don't tell us when their sender joined the server
+    if bot.config.min_tenure > 0 {
+        println!("Old messages can't be checked against the minimum tenure, so people who earn the role from them get it on their next message");
+    }
+    for grant in bot.backfill(history) {
+        let name = bot.names.get(grant.user);
+        granter.grant(grant, name, &bot.config, &mut bot.message_map);
+    }
+}
+
+/// Fetch up to `limit` of the most recent messages in a channel, a page at a time.
+/// The client waits out discord's rate limits for us.
+async fn fetch_channel(
+    client: &Client,
+    channel: Id<ChannelMarker>,
+    limit: usize,
+    history: &mut Vec<Message>,
+) {
+    let mut fetched = 0;
+    let mut before = None;
+    while fetched < limit {
+        // This can't fail, since it's never more than PAGE_SIZE
+        let page_size = u16::try_from((limit - fetched).min(PAGE_SIZE)).unwrap_or(100);
+        let request = client.channel_messages(channel);
+        // The first page is the newest messages, and every page after that is older than the last
+        let response = match before {
+            Some(before) => request.before(before).limit(page_size).await,
+            None => request.limit(page_size).await,
+        };
+        let page = match response {
+            Ok(response) => response.models().await,
+            Err(error) => {
+                eprintln!("ERROR: could not fetch old messages from {channel}: {error:?}");
+                return;
+            }
+        };
+        let page = match page {
+            Ok(page) => page,
+            Err(error) => {
+                eprintln!("ERROR: could not read old messages from {channel}: {error:?}");
+                return;
+            }
+        };
+        // Discord gives us the newest messages first, so the last one is the oldest
+        let Some(oldest) = page.last() else {
+            return;
+        };
+        before = Some(oldest.id);
+        fetched += page.len();
+        let reached_start = page.len() < usize::from(page_size);
+        history.extend(page);
+        // If discord gave us less than we asked for, we've reached the start of the channel
+        if reached_start {
+            return;
+        }
+    }
+}
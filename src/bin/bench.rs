@@ -6,85 +6,130 @@ use ephemerole::{AssignConfig, MessageMap};
 use twilight_model::{
     channel::{message::MessageType, Message},
     gateway::payload::incoming::MessageCreate,
-    id::Id,
+    guild::{MemberFlags, PartialMember},
+    id::{marker::RoleMarker, Id},
     user::User,
     util::Timestamp,
 };
 
+/// How many roles each member has in the `granted` workload. Big servers hand out lots of roles,
+/// and the one we give out is last, so finding it means looking through all of them.
+const ROLES_PER_MEMBER: u64 = 25;
+
+/// Run with `bench [workload] [messages]`. The `new` workload (the default) is users working
+/// towards the role, and the `granted` workload is users who already have it, which is what
+/// most messages look like once a server has been using the bot for a while.
 fn main() {
-    let message_count = 1_000_000_000;
-    let started = Instant::now();
-    let config = AssignConfig {
-        role: Id::new(1),
-        message_cooldown: 60,
-        message_requirement: 60,
+    let mut args = std::env::args().skip(1);
+    let workload = args.next().unwrap_or_else(|| "new".to_owned());
+    let message_count: usize = args.next().map_or(1_000_000_000, |count| {
+        count.parse().expect("messages must be a number")
+    });
+    let config = AssignConfig::new(Id::new(1));
+    // Users who already have the role have lots of other roles too, with ours at the end
+    let member = match workload.as_str() {
+        "new" => None,
+        "granted" => Some(member(
+            (2..=ROLES_PER_MEMBER).chain([1]).map(Id::new).collect(),
+        )),
+        _ => panic!("workload must be new or granted"),
     };
+    // Only the author and ID change between messages, so we make one message and change those,
+    // instead of timing how long it takes to build a message
+    let mut msg = message(member);
     let mut messages = MessageMap::new();
+    let started = Instant::now();
     for (seq, i) in (1..100_000).cycle().take(message_count).enumerate() {
-        let author = User {
-            avatar_decoration_data: None,
-            accent_color: None,
-            avatar: None,
-            avatar_decoration: None,
-            banner: None,
-            bot: false,
-            discriminator: 0,
-            email: None,
-            flags: None,
-            global_name: None,
-            id: Id::new(i),
-            locale: None,
-            mfa_enabled: None,
-            name: String::new(),
-            premium_type: None,
-            public_flags: None,
-            system: None,
-            verified: None,
-        };
-        #[allow(deprecated)]
-        let msg = Message {
-            poll: None,
-            activity: None,
-            application: None,
-            application_id: None,
-            attachments: vec![],
-            author,
-            channel_id: Id::new(1),
-            components: vec![],
-            content: String::new(),
-            edited_timestamp: None,
-            embeds: vec![],
-            flags: None,
-            guild_id: None,
-            id: Id::new(1),
-            interaction: None,
-            call: None,
-            interaction_metadata: None,
-            message_snapshots: vec![],
-            kind: MessageType::Regular,
-            member: None,
-            mention_channels: vec![],
-            mention_everyone: false,
-            mention_roles: vec![],
-            mentions: vec![],
-            pinned: false,
-            reactions: vec![],
-            reference: None,
-            referenced_message: None,
-            role_subscription_data: None,
-            sticker_items: vec![],
-            timestamp: Timestamp::from_secs(seq.try_into().unwrap()).unwrap(),
-            thread: None,
-            tts: false,
-            webhook_id: None,
-        };
-        let msg = MessageCreate(msg);
-        std::hint::black_box(ephemerole::should_assign_role(&msg, config, &mut messages));
+        msg.author.id = Id::new(i);
+        // When a message was sent comes from its ID, so each one gets an ID a second after the last.
+        // That puts each user's messages far enough apart to count, and they get the role every so often.
+        let sent_at: u64 = seq.try_into().unwrap();
+        msg.id = Id::new(((sent_at * 1000) << 22) | 1);
+        std::hint::black_box(ephemerole::should_assign_role(&msg, &config, &mut messages));
     }
     let elapsed = started.elapsed();
     println!(
-        "Took {} seconds to process 1,000,000,000 messages from 100,000 users ({} ns/iter)",
+        "Took {} seconds to process {message_count} {workload} messages from 100,000 users ({} ns/iter)",
         elapsed.as_secs_f64(),
         elapsed.as_nanos() / message_count as u128
     );
 }
+
+/// A member of the server with these roles
+const fn member(roles: Vec<Id<RoleMarker>>) -> PartialMember {
+    PartialMember {
+        avatar: None,
+        communication_disabled_until: None,
+        deaf: false,
+        flags: MemberFlags::empty(),
+        joined_at: None,
+        mute: false,
+        nick: None,
+        permissions: None,
+        premium_since: None,
+        roles,
+        user: None,
+    }
+}
+
+/// A normal message, sent by `member` if we know who they are in the server
+fn message(member: Option<PartialMember>) -> MessageCreate {
+    let author = User {
+        avatar_decoration_data: None,
+        accent_color: None,
+        avatar: None,
+        avatar_decoration: None,
+        banner: None,
+        bot: false,
+        discriminator: 0,
+        email: None,
+        flags: None,
+        global_name: None,
+        id: Id::new(1),
+        locale: None,
+        mfa_enabled: None,
+        name: String::new(),
+        premium_type: None,
+        public_flags: None,
+        system: None,
+        verified: None,
+    };
+    #[allow(deprecated)]
+    let msg = Message {
+        poll: None,
+        activity: None,
+        application: None,
+        application_id: None,
+        attachments: vec![],
+        author,
+        channel_id: Id::new(1),
+        components: vec![],
+        content: String::new(),
+        edited_timestamp: None,
+        embeds: vec![],
+        flags: None,
+        guild_id: None,
+        id: Id::new(1),
+        interaction: None,
+        call: None,
+        interaction_metadata: None,
+        message_snapshots: vec![],
+        kind: MessageType::Regular,
+        member,
+        mention_channels: vec![],
+        mention_everyone: false,
+        mention_roles: vec![],
+        mentions: vec![],
+        pinned: false,
+        reactions: vec![],
+        reference: None,
+        referenced_message: None,
+        role_subscription_data: None,
+        sticker_items: vec![],
+        timestamp: Timestamp::from_secs(0).unwrap(),
+        thread: None,
+        tts: false,
+        webhook_id: None,
+    };
+    MessageCreate(msg)
+}
@@ -0,0 +1,110 @@
+#![warn(clippy::all, clippy::pedantic, clippy::nursery)]
+//! Feed a log of real messages through the bot, without connecting to discord, to see exactly
+//! what it would have done.
+//!
+//! The log has one message per line, as the JSON discord sends for `MESSAGE_CREATE`.
+//! Usage: `replay <log.jsonl>`. What it takes to get the role is read from the same environment
+//! variables as the bot, including `EPHEMEROLE_PROFILE`, so set them up the same way.
+
+use std::{
+    fs::File,
+    io::{stdout, BufRead, BufReader},
+};
+
+use ephemerole::{AssignConfig, Bot, Grant};
+use twilight_model::gateway::{event::Event, payload::incoming::MessageCreate};
+
+fn main() {
+    let path = std::env::args().nth(1).expect("Usage: replay <log.jsonl>");
+    let config = AssignConfig::from_env();
+    for warning in ephemerole::validate_config(&config) {
+        eprintln!("WARN: {warning}");
+    }
+
+    let file = File::open(&path).unwrap_or_else(|error| panic!("Could not open {path}: {error}"));
+    // We don't need anyone's names, so don't remember any
+    let mut bot = Bot::new(config, 0, 0);
+    let replayed = replay(BufReader::new(file), &mut bot);
+    for grant in &replayed.grants {
+        println!(
+            "Would give {} the role for their message at {} (first seen at {})",
+            grant.user, grant.sent_at, grant.first_seen_at
+        );
+    }
+    println!(
+        "Replayed {} messages, giving out {} roles. Everyone still being tracked:",
+        replayed.messages,
+        replayed.grants.len()
+    );
+    ephemerole::export_json(&bot.message_map, stdout().lock()).expect("Could not write the map");
+    println!();
+}
+
+/// What happened when we replayed a log
+struct Replayed {
+    /// How many messages were in it, not counting lines we couldn't read
+    messages: usize,
+    /// Everyone who would have gotten the role, in order
+    grants: Vec<Grant>,
+}
+
+/// Feed every message in `log` through `bot`
+fn replay(log: impl BufRead, bot: &mut Bot) -> Replayed {
+    let mut replayed = Replayed {
+        messages: 0,
+        grants: Vec::new(),
+    };
+    for (number, line) in log.lines().enumerate() {
+        let line = line.unwrap_or_else(|error| panic!("Could not read the log: {error}"));
+        if line.trim().is_empty() {
+            continue;
+        }
+        // A broken line is reported and skipped, so one bad capture doesn't stop the whole replay
+        let message_create: MessageCreate = match serde_json::from_str(&line) {
+            Ok(message_create) => message_create,
+            Err(error) => {
+                eprintln!("ERROR: could not read line {}: {error}", number + 1);
+                continue;
+            }
+        };
+        replayed.messages += 1;
+        // Going through the same function as the bot means repeats are skipped just like they would be live
+        let event = Event::MessageCreate(Box::new(message_create));
+        replayed.grants.extend(bot.process(&event));
+    }
+    replayed
+}
+
+#[cfg(test)]
+mod tests {
+    use twilight_model::id::Id;
+
+    use super::*;
+
+    #[test]
+    fn replays_sample_log() {
+        let config = AssignConfig {
+            message_requirement: 2,
+            ..AssignConfig::new(Id::new(2))
+        };
+        let mut bot = Bot::new(config, 0, 0);
+        let log = include_str!("../../testdata/replay.jsonl");
+        let replayed = replay(log.as_bytes(), &mut bot);
+        // The line that isn't a message and the blank line are skipped
+        assert_eq!(replayed.messages, 7);
+        // User 5 earns the role with their fourth message, since the second was too soon
+        assert_eq!(
+            replayed.grants,
+            [Grant {
+                user: Id::new(5),
+                sent_at: 120,
+                first_seen_at: 0,
+                messages: 2,
+            }]
+        );
+        // User 6 already has the role, and user 7's first message is a repeat, so only their second counts
+        assert_eq!(bot.message_map.len(), 1);
+        assert_eq!(bot.message_map[&Id::new(7)].messages, 1);
+        assert_eq!(bot.message_map[&Id::new(7)].first_seen_at, 140);
+    }
+}
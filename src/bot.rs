@@ -0,0 +1,140 @@
+use twilight_model::{channel::Message, gateway::event::Event};
+
+use crate::{AssignConfig, Grant, MessageMap, NameCache, SeenMessages};
+
+/// Everything the bot needs to decide who gets the role, all in one place.
+///
+/// This never talks to discord. Feed it events with [`Bot::process`], and give the role to
+/// whoever it says has earned it. The fields are public, so anything it doesn't cover can
+/// still be done with the free functions.
+#[derive(Clone)]
+pub struct Bot {
+    /// What it takes to get the role
+    pub config: AssignConfig,
+    /// Everyone we're keeping track of
+    pub message_map: MessageMap,
+    /// The names of people who've sent messages recently, so logs can say who they are
+    pub names: NameCache,
+    /// The newest message in each channel, so we don't count messages discord sends us again
+    pub seen: SeenMessages,
+}
+
+impl Bot {
+    /// Make a bot that isn't keeping track of anyone yet. There's room for `expected_users`
+    /// up front, and it remembers at most `name_cache_size` names.
+    #[must_use]
+    pub fn new(config: AssignConfig, expected_users: usize, name_cache_size: usize) -> Self {
+        Self {
+            config,
+            message_map: MessageMap::with_capacity(expected_users),
+            names: NameCache::new(name_cache_size),
+            seen: SeenMessages::new(),
+        }
+    }
+
+    /// Look at an event from discord, and say who gets the role because of it, if anyone
+    pub fn process(&mut self, event: &Event) -> Option<Grant> {
+        if let Event::MessageCreate(message_create) = event {
+            self.names.insert(&message_create.author);
+        }
+        crate::handle_event(event, &self.config, &mut self.message_map, &mut self.seen)
+    }
+
+    /// Count messages from before we started, and say who earned the role from them.
+    /// See [`crate::backfill`].
+    pub fn backfill(&mut self, messages: Vec<Message>) -> Vec<Grant> {
+        for message in &messages {
+            self.names.insert(&message.author);
+        }
+        crate::backfill(messages, &self.config, &mut self.message_map)
+    }
+
+    /// Stop keeping track of everyone who has already earned the role under the current config,
+    /// and say who they are, so they can be given it without waiting for their next message.
+    /// While we're only tracking messages, nobody is.
+    pub fn take_eligible(&mut self) -> Vec<Grant> {
+        if self.config.tracking_only {
+            return Vec::new();
+        }
+        crate::recompute_eligible(&self.message_map, &self.config)
+            .into_iter()
+            .filter_map(|user| {
+                let data = self.message_map.remove(&user)?;
+                Some(Grant {
+                    user,
+                    sent_at: data.last_message_at,
+                    first_seen_at: data.first_seen_at,
+                    messages: data.messages,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use twilight_model::id::Id;
+
+    use super::*;
+    use crate::tests::created;
+
+    /// A bot that gives the role for 2 messages, but is only tracking them for now
+    fn tracking_only_bot() -> Bot {
+        let config = AssignConfig {
+            message_requirement: 2,
+            tracking_only: true,
+            ..AssignConfig::new(Id::new(1))
+        };
+        Bot::new(config, 0, 0)
+    }
+
+    #[test]
+    fn tracking_only_grants_nobody() {
+        let mut bot = tracking_only_bot();
+        for sent_at in [0, 60, 120, 180] {
+            assert_eq!(bot.process(&created(sent_at, 5)), None);
+        }
+        // They kept earning progress the whole time
+        assert_eq!(bot.message_map[&Id::new(5)].messages, 4);
+        assert!(bot.take_eligible().is_empty());
+    }
+
+    #[test]
+    fn turning_tracking_only_off_grants_who_qualified() {
+        let mut bot = tracking_only_bot();
+        // Messages all go in one channel, so each needs its own time to not look like a repeat
+        for sent_at in [0, 60] {
+            bot.process(&created(sent_at, 5));
+            bot.process(&created(sent_at + 1, 7));
+        }
+        bot.process(&created(62, 6));
+        bot.config.tracking_only = false;
+        let eligible = bot.take_eligible();
+        assert_eq!(
+            eligible,
+            [
+                Grant {
+                    user: Id::new(5),
+                    sent_at: 60,
+                    first_seen_at: 0,
+                    messages: 2,
+                },
+                Grant {
+                    user: Id::new(7),
+                    sent_at: 61,
+                    first_seen_at: 1,
+                    messages: 2,
+                },
+            ]
+        );
+        // User 6 hasn't sent enough yet, so they're still being tracked
+        assert_eq!(bot.message_map.keys().collect::<Vec<_>>(), [&Id::new(6)]);
+    }
+
+    #[test]
+    fn makes_room_for_expected_users() {
+        let bot = Bot::new(AssignConfig::new(Id::new(1)), 10_000, 0);
+        assert!(bot.message_map.capacity() >= 10_000);
+        assert!(bot.message_map.is_empty());
+    }
+}
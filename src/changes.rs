@@ -0,0 +1,338 @@
+use std::io::{Error as IoError, ErrorKind, Read, Write};
+
+use twilight_model::id::{marker::UserMarker, Id};
+
+use crate::{MessageMap, UserData};
+
+/// The first bytes of every change file, so we know it's actually one of ours
+const MAGIC: [u8; 4] = *b"EPDC";
+/// Which version of the change file layout we write. Bump this whenever the layout changes.
+const VERSION: u8 = 1;
+
+/// Record kinds, as they're written to disk
+const OP_INSERT: u8 = 1;
+const OP_UPDATE: u8 = 2;
+const OP_REMOVE: u8 = 3;
+
+/// One difference between two message maps
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MapChange {
+    /// A user we weren't tracking before
+    Insert(Id<UserMarker>, UserData),
+    /// A user whose data changed
+    Update(Id<UserMarker>, UserData),
+    /// A user we stopped tracking, usually because they got the role
+    Remove(Id<UserMarker>),
+}
+
+/// Work out what changed to get from `old` to `new`. The changes are sorted by user ID,
+/// so the same two maps always give the same list.
+#[must_use]
+pub fn diff(old: &MessageMap, new: &MessageMap) -> Vec<MapChange> {
+    let mut changes: Vec<MapChange> = new
+        .iter()
+        .filter_map(|(user, data)| match old.get(user) {
+            None => Some(MapChange::Insert(*user, *data)),
+            Some(old_data) if old_data != data => Some(MapChange::Update(*user, *data)),
+            Some(_) => None,
+        })
+        .collect();
+    changes.extend(
+        old.keys()
+            .filter(|user| !new.contains_key(user))
+            .map(|user| MapChange::Remove(*user)),
+    );
+    changes.sort_unstable_by_key(|change| match change {
+        MapChange::Insert(user, _) | MapChange::Update(user, _) | MapChange::Remove(user) => *user,
+    });
+    changes
+}
+
+/// Make the changes in `changes` to `message_map`, so `apply(old, diff(old, new))` turns `old` into `new`
+pub fn apply(message_map: &mut MessageMap, changes: &[MapChange]) {
+    for change in changes {
+        match change {
+            MapChange::Insert(user, data) | MapChange::Update(user, data) => {
+                message_map.insert(*user, *data);
+            }
+            MapChange::Remove(user) => {
+                message_map.remove(user);
+            }
+        }
+    }
+}
+
+/// Write a list of changes in a compact binary layout.
+///
+/// The layout is the magic bytes, a version byte, the number of records as a little-endian u64,
+/// then each record as an op byte and ten little-endian u64s (user ID, message count, last
+/// message time, window start, messages in window, first seen time, partial points, partial
+/// points since the last counted message, streak, last active day), and finally a checksum of
+/// everything before it.
+///
+/// # Errors
+/// If the writer fails.
+pub fn write_changes(changes: &[MapChange], mut writer: impl Write) -> Result<(), IoError> {
+    let mut writer = ChecksumWriter::new(&mut writer);
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&[VERSION])?;
+    writer.write_all(&(changes.len() as u64).to_le_bytes())?;
+    for change in changes {
+        // Removals don't have any data, so they write zeroes to keep every record the same size
+        let (op, user, data) = match change {
+            MapChange::Insert(user, data) => (OP_INSERT, user, *data),
+            MapChange::Update(user, data) => (OP_UPDATE, user, *data),
+            MapChange::Remove(user) => (
+                OP_REMOVE,
+                user,
+                UserData {
+                    messages: 0,
+                    last_message_at: 0,
+                    window_start: 0,
+                    window_messages: 0,
+                    first_seen_at: 0,
+                    partial_points: 0,
+                    partial_window_points: 0,
+                    streak: 0,
+                    last_active_day: 0,
+                },
+            ),
+        };
+        writer.write_all(&[op])?;
+        writer.write_all(&user.get().to_le_bytes())?;
+        writer.write_all(&data.messages.to_le_bytes())?;
+        writer.write_all(&data.last_message_at.to_le_bytes())?;
+        writer.write_all(&data.window_start.to_le_bytes())?;
+        writer.write_all(&data.window_messages.to_le_bytes())?;
+        writer.write_all(&data.first_seen_at.to_le_bytes())?;
+        writer.write_all(&data.partial_points.to_le_bytes())?;
+        writer.write_all(&data.partial_window_points.to_le_bytes())?;
+        writer.write_all(&data.streak.to_le_bytes())?;
+        writer.write_all(&data.last_active_day.to_le_bytes())?;
+    }
+    let checksum = writer.checksum;
+    writer.write_all(&checksum.to_le_bytes())?;
+    Ok(())
+}
+
+/// Read a list of changes written by [`write_changes`].
+///
+/// # Errors
+/// If the reader fails, or the data isn't a valid change file (wrong magic bytes or version,
+/// an unknown record kind, a user ID of zero, or a checksum that doesn't match).
+pub fn read_changes(mut reader: impl Read) -> Result<Vec<MapChange>, IoError> {
+    let mut reader = ChecksumReader::new(&mut reader);
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(invalid("not a change file"));
+    }
+    let [version] = read_array(&mut reader)?;
+    if version != VERSION {
+        return Err(invalid("unsupported change file version"));
+    }
+    let count = u64::from_le_bytes(read_array(&mut reader)?);
+    // Don't trust the count to tell us how much memory to grab, it might be garbage
+    let mut changes = Vec::with_capacity(usize::try_from(count).unwrap_or(0).min(1024));
+    for _ in 0..count {
+        let [op] = read_array(&mut reader)?;
+        let user = Id::new_checked(u64::from_le_bytes(read_array(&mut reader)?))
+            .ok_or_else(|| invalid("user ID of zero"))?;
+        let mut read_u64 = || read_array(&mut reader).map(u64::from_le_bytes);
+        let data = UserData {
+            messages: read_u64()?,
+            last_message_at: read_u64()?,
+            window_start: read_u64()?,
+            window_messages: read_u64()?,
+            first_seen_at: read_u64()?,
+            partial_points: read_u64()?,
+            partial_window_points: read_u64()?,
+            streak: read_u64()?,
+            last_active_day: read_u64()?,
+        };
+        changes.push(match op {
+            OP_INSERT => MapChange::Insert(user, data),
+            OP_UPDATE => MapChange::Update(user, data),
+            OP_REMOVE => MapChange::Remove(user),
+            _ => return Err(invalid("unknown change kind")),
+        });
+    }
+    let expected = reader.checksum;
+    let checksum = u64::from_le_bytes(read_array(&mut reader)?);
+    if checksum != expected {
+        return Err(invalid("checksum mismatch"));
+    }
+    Ok(changes)
+}
+
+fn invalid(message: &str) -> IoError {
+    IoError::new(ErrorKind::InvalidData, message)
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> Result<[u8; N], IoError> {
+    let mut bytes = [0; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// The FNV-1a hash. It isn't cryptographic, it just catches files that got damaged.
+const FNV_OFFSET: u64 = 0xCBF2_9CE4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01B3;
+
+fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+/// Passes writes through, keeping a checksum of everything written so far
+struct ChecksumWriter<W> {
+    inner: W,
+    checksum: u64,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    const fn new(inner: W) -> Self {
+        Self {
+            inner,
+            checksum: FNV_OFFSET,
+        }
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        let written = self.inner.write(buf)?;
+        self.checksum = fnv1a(self.checksum, &buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.inner.flush()
+    }
+}
+
+/// Passes reads through, keeping a checksum of everything read so far
+struct ChecksumReader<R> {
+    inner: R,
+    checksum: u64,
+}
+
+impl<R: Read> ChecksumReader<R> {
+    const fn new(inner: R) -> Self {
+        Self {
+            inner,
+            checksum: FNV_OFFSET,
+        }
+    }
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        let read = self.inner.read(buf)?;
+        self.checksum = fnv1a(self.checksum, &buf[..read]);
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Someone who has sent `messages` messages, the last one at `last_message_at`
+    fn user_data(messages: u64, last_message_at: u64) -> UserData {
+        UserData {
+            messages,
+            last_message_at,
+            ..UserData::first_message(0)
+        }
+    }
+
+    /// Two maps with a user added, one changed, one removed, and one left alone
+    fn maps() -> (MessageMap, MessageMap) {
+        let old = MessageMap::from_iter([
+            (Id::new(1), user_data(1, 10)),
+            (Id::new(2), user_data(2, 20)),
+            (Id::new(3), user_data(3, 30)),
+        ]);
+        let new = MessageMap::from_iter([
+            (Id::new(1), user_data(1, 10)),
+            (Id::new(2), user_data(3, 80)),
+            (Id::new(4), user_data(1, 90)),
+        ]);
+        (old, new)
+    }
+
+    #[test]
+    fn diff_then_apply_gives_the_new_map() {
+        let (old, new) = maps();
+        let changes = diff(&old, &new);
+        assert_eq!(
+            changes,
+            [
+                MapChange::Update(Id::new(2), new[&Id::new(2)]),
+                MapChange::Remove(Id::new(3)),
+                MapChange::Insert(Id::new(4), new[&Id::new(4)]),
+            ]
+        );
+        let mut patched = old;
+        apply(&mut patched, &changes);
+        assert_eq!(patched, new);
+    }
+
+    /// The changes between the test maps, written out
+    fn written() -> Vec<u8> {
+        let (old, new) = maps();
+        let mut bytes = Vec::new();
+        write_changes(&diff(&old, &new), &mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn write_then_read_gives_the_same_changes() {
+        let (old, new) = maps();
+        assert_eq!(
+            read_changes(written().as_slice()).unwrap(),
+            diff(&old, &new)
+        );
+    }
+
+    /// Reading `bytes` fails as invalid data
+    fn assert_invalid(bytes: &[u8]) {
+        let error = read_changes(bytes).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let mut bytes = written();
+        bytes[0] = b'X';
+        assert_invalid(&bytes);
+    }
+
+    #[test]
+    fn rejects_unknown_versions() {
+        let mut bytes = written();
+        bytes[4] = 0;
+        assert_invalid(&bytes);
+        bytes[4] = VERSION + 1;
+        assert_invalid(&bytes);
+    }
+
+    #[test]
+    fn rejects_damaged_files() {
+        let mut bytes = written();
+        // Flip a bit in the first record's message count
+        bytes[4 + 1 + 8 + 1 + 8] ^= 1;
+        assert_invalid(&bytes);
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let bytes = written();
+        let error = read_changes(&bytes[..bytes.len() - 1]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,379 @@
+//! Slash commands, so server staff can look at and change how the bot works while it's running
+
+use std::{future::Future, sync::Arc};
+
+use ephemerole::{AssignConfig, Bot, Cadence};
+use twilight_http::Client;
+use twilight_model::{
+    application::{
+        command::{CommandOption, CommandOptionType, CommandOptionValue as OptionLimit},
+        interaction::{
+            application_command::{CommandData, CommandOptionValue},
+            Interaction, InteractionData,
+        },
+    },
+    channel::message::{AllowedMentions, MessageFlags},
+    guild::Permissions,
+    http::interaction::{InteractionResponse, InteractionResponseData, InteractionResponseType},
+    id::{
+        marker::{GuildMarker, UserMarker},
+        Id,
+    },
+};
+
+use crate::leaderboard::LeaderboardCache;
+
+/// The name of the command that changes the message requirement and cooldown
+const CONFIGURE: &str = "configure";
+/// The name of the command that shows who is closest to getting the role
+const LEADERBOARD: &str = "leaderboard";
+/// How many users to show on the leaderboard
+pub const LEADERBOARD_SIZE: usize = 10;
+
+/// Tell discord about our commands, so they show up in the server
+pub async fn register(client: Arc<Client>, guild: Id<GuildMarker>) {
+    // Commands belong to our application, so we need to know what its ID is
+    let application = match client.current_user_application().await {
+        Ok(response) => response.model().await,
+        Err(error) => {
+            eprintln!("ERROR: could not get application to register commands: {error:?}");
+            return;
+        }
+    };
+    let application = match application {
+        Ok(application) => application,
+        Err(error) => {
+            eprintln!("ERROR: could not read application to register commands: {error:?}");
+            return;
+        }
+    };
+
+    let configure_options = [
+        number_option(
+            "requirement",
+            "How many messages, or days in a row with STREAK_DAYS, it takes to get the role",
+        ),
+        number_option(
+            "cooldown",
+            "How many seconds apart messages must be to count",
+        ),
+        boolean_option(
+            "tracking_only",
+            "Keep counting messages, but don't give anyone the role",
+        ),
+    ];
+    // Only people who can manage the server can change the config
+    if let Err(error) = client
+        .interaction(application.id)
+        .create_guild_command(guild)
+        .chat_input(CONFIGURE, "Change what it takes to get the role")
+        .default_member_permissions(Permissions::MANAGE_GUILD)
+        .command_options(&configure_options)
+        .await
+    {
+        eprintln!("ERROR: could not register /{CONFIGURE} command: {error:?}");
+    }
+    // Anyone can look at the leaderboard
+    if let Err(error) = client
+        .interaction(application.id)
+        .create_guild_command(guild)
+        .chat_input(LEADERBOARD, "See who is closest to getting the role")
+        .await
+    {
+        eprintln!("ERROR: could not register /{LEADERBOARD} command: {error:?}");
+    }
+}
+
+/// Run a command someone used, and work out what to tell them.
+/// Returns `None` if the interaction isn't one of our commands.
+pub fn handle(
+    interaction: &Interaction,
+    guild: Id<GuildMarker>,
+    bot: &mut Bot,
+    leaderboard_cache: &LeaderboardCache,
+) -> Option<String> {
+    // We only run commands in the server we're working in
+    if interaction.guild_id != Some(guild) {
+        return None;
+    }
+    let Some(InteractionData::ApplicationCommand(data)) = &interaction.data else {
+        return None;
+    };
+    match data.name.as_str() {
+        // Server admins can let anyone use the command in their settings, so check for ourselves too
+        CONFIGURE if !can_manage_guild(interaction) => {
+            Some("You need the Manage Server permission to change the config.".to_owned())
+        }
+        CONFIGURE => Some(configure(data, &mut bot.config)),
+        LEADERBOARD => Some(leaderboard(
+            &leaderboard_cache.get(&bot.message_map, &bot.config).top,
+            &bot.config,
+        )),
+        _ => None,
+    }
+}
+
+/// Does the person who used the command have the Manage Server permission?
+fn can_manage_guild(interaction: &Interaction) -> bool {
+    interaction
+        .member
+        .as_ref()
+        .and_then(|member| member.permissions)
+        .is_some_and(|permissions| permissions.contains(Permissions::MANAGE_GUILD))
+}
+
+/// Change the requirement and cooldown, leaving everyone's progress alone.
+/// Users are checked against the new values on their next message.
+/// If it takes a streak, the requirement is how many days in a row, instead of how many messages.
+/// Setting a cooldown switches away from a message window, if there was one.
+/// Turning tracking only off gives the role to everyone who earned it in the meantime.
+fn configure(data: &CommandData, config: &mut AssignConfig) -> String {
+    if let Some(requirement) = number_value(data, "requirement") {
+        if config.streak_days.is_some() {
+            config.streak_days = Some(requirement);
+        } else {
+            config.message_requirement = requirement;
+        }
+    }
+    if let Some(cooldown) = number_value(data, "cooldown") {
+        config.cadence = Cadence::MinGap(cooldown);
+    }
+    if let Some(tracking_only) = boolean_value(data, "tracking_only") {
+        config.tracking_only = tracking_only;
+    }
+    let requirement = match config.streak_days {
+        Some(days) => format!("a counted message on {days} days in a row"),
+        None => format!("{} messages", config.message_requirement),
+    };
+    println!(
+        "Config changed: requirement is {requirement}, {}",
+        config.cadence
+    );
+    let mut reply = format!(
+        "Users now need {requirement}, {}, to get the role.",
+        config.cadence
+    );
+    if config.tracking_only {
+        println!("Only tracking messages, so nobody gets the role");
+        reply.push_str(
+            "\nMessages are only being tracked, so nobody gets the role until this is turned off.",
+        );
+    }
+    // Let them know if they've probably made a mistake
+    for warning in ephemerole::validate_config(config) {
+        reply.push_str("\nWarning: ");
+        reply.push_str(&warning.to_string());
+    }
+    reply
+}
+
+/// List the users closest to getting the role who don't have it yet.
+/// If it takes a streak, that's their streak, otherwise it's their messages.
+fn leaderboard(top: &[(Id<UserMarker>, u64)], config: &AssignConfig) -> String {
+    if top.is_empty() {
+        return "Nobody is working towards the role right now.".to_owned();
+    }
+    let unit = if config.streak_days.is_some() {
+        "days in a row"
+    } else {
+        "messages"
+    };
+    // Mentions show up as usernames in discord, so we don't need to look anybody up
+    top.iter()
+        .enumerate()
+        .map(|(place, (user, progress))| format!("{}. <@{user}>: {progress} {unit}", place + 1))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Answer a command privately, so only the person who ran it sees the reply.
+/// We never ping anyone we mention in a reply.
+///
+/// This copies what it needs out of the interaction, so the reply can be sent in the background.
+pub fn respond(
+    client: Arc<Client>,
+    interaction: &Interaction,
+    content: String,
+) -> impl Future<Output = ()> + Send + 'static {
+    let application = interaction.application_id;
+    let token = interaction.token.clone();
+    let interaction = interaction.id;
+    async move {
+        let response = InteractionResponse {
+            kind: InteractionResponseType::ChannelMessageWithSource,
+            data: Some(InteractionResponseData {
+                allowed_mentions: Some(AllowedMentions::default()),
+                content: Some(content),
+                flags: Some(MessageFlags::EPHEMERAL),
+                ..Default::default()
+            }),
+        };
+        if let Err(error) = client
+            .interaction(application)
+            .create_response(interaction, &token, &response)
+            .await
+        {
+            eprintln!("ERROR: could not respond to command: {error:?}");
+        }
+    }
+}
+
+/// Describe an optional whole-number option that can't be negative
+fn number_option(name: &str, description: &str) -> CommandOption {
+    CommandOption {
+        autocomplete: None,
+        channel_types: None,
+        choices: None,
+        description: description.to_owned(),
+        description_localizations: None,
+        kind: CommandOptionType::Integer,
+        max_length: None,
+        max_value: None,
+        min_length: None,
+        min_value: Some(OptionLimit::Integer(0)),
+        name: name.to_owned(),
+        name_localizations: None,
+        options: None,
+        required: Some(false),
+    }
+}
+
+/// Describe an optional yes-or-no option
+fn boolean_option(name: &str, description: &str) -> CommandOption {
+    CommandOption {
+        kind: CommandOptionType::Boolean,
+        min_value: None,
+        ..number_option(name, description)
+    }
+}
+
+/// Get the value of a yes-or-no option, if the user filled it in
+fn boolean_value(data: &CommandData, name: &str) -> Option<bool> {
+    data.options
+        .iter()
+        .find(|option| option.name == name)
+        .and_then(|option| match option.value {
+            CommandOptionValue::Boolean(value) => Some(value),
+            _ => None,
+        })
+}
+
+/// Get the value of a whole-number option, if the user filled it in
+fn number_value(data: &CommandData, name: &str) -> Option<u64> {
+    data.options
+        .iter()
+        .find(|option| option.name == name)
+        .and_then(|option| match option.value {
+            CommandOptionValue::Integer(value) => value.try_into().ok(),
+            _ => None,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use ephemerole::UserData;
+
+    use super::*;
+
+    /// The bot's default config
+    fn config() -> AssignConfig {
+        AssignConfig::new(Id::new(1))
+    }
+
+    /// Someone with these permissions using `/configure` to set the requirement to `requirement`
+    fn configure_interaction(permissions: Permissions, requirement: u64) -> Interaction {
+        serde_json::from_value(serde_json::json!({
+            "id": "1",
+            "application_id": "1",
+            "type": 2,
+            "token": "token",
+            "version": 1,
+            "authorizing_integration_owners": {},
+            "entitlements": [],
+            "guild_id": "1",
+            "member": {
+                "deaf": false,
+                "mute": false,
+                "flags": 0,
+                "joined_at": null,
+                "roles": [],
+                "permissions": permissions.bits().to_string(),
+                "user": { "id": "5", "username": "someone", "discriminator": "0", "avatar": null },
+            },
+            "data": {
+                "id": "1",
+                "name": CONFIGURE,
+                "type": 1,
+                "options": [{ "name": "requirement", "type": 4, "value": requirement }],
+            },
+        }))
+        .unwrap()
+    }
+
+    /// A bot with user 5 partway to the role, with 3 messages
+    fn bot_with_pending_user() -> Bot {
+        let mut bot = Bot::new(config(), 0, 0);
+        let data = UserData {
+            messages: 3,
+            ..UserData::first_message(0)
+        };
+        bot.message_map.insert(Id::new(5), data);
+        bot
+    }
+
+    #[test]
+    fn configure_needs_manage_guild() {
+        let mut bot = bot_with_pending_user();
+        let cache = LeaderboardCache::new(None, LEADERBOARD_SIZE);
+        let interaction = configure_interaction(Permissions::SEND_MESSAGES, 3);
+        let reply = handle(&interaction, Id::new(1), &mut bot, &cache).unwrap();
+        assert!(reply.contains("Manage Server"));
+        assert_eq!(bot.config.message_requirement, 60);
+    }
+
+    #[test]
+    fn lowering_requirement_makes_pending_users_eligible() {
+        let mut bot = bot_with_pending_user();
+        let old_config = bot.config.clone();
+        let cache = LeaderboardCache::new(None, LEADERBOARD_SIZE);
+        let interaction = configure_interaction(Permissions::MANAGE_GUILD, 3);
+        let reply = handle(&interaction, Id::new(1), &mut bot, &cache).unwrap();
+        assert!(reply.starts_with("Users now need 3 messages"));
+        assert!(crate::opens_up(&old_config, &bot.config));
+        let eligible = bot.take_eligible();
+        assert_eq!(eligible.len(), 1);
+        assert_eq!(eligible[0].user, Id::new(5));
+        assert!(bot.message_map.is_empty());
+        // Raising it again doesn't open anything up
+        assert!(!crate::opens_up(&bot.config, &old_config));
+    }
+
+    #[test]
+    fn leaderboard_lists_users_in_order() {
+        let top = [(Id::new(2), 9), (Id::new(5), 4)];
+        assert_eq!(
+            leaderboard(&top, &config()),
+            "1. <@2>: 9 messages\n2. <@5>: 4 messages"
+        );
+    }
+
+    #[test]
+    fn streak_leaderboard() {
+        let config = AssignConfig {
+            streak_days: Some(7),
+            ..config()
+        };
+        assert_eq!(
+            leaderboard(&[(Id::new(2), 3)], &config),
+            "1. <@2>: 3 days in a row"
+        );
+    }
+
+    #[test]
+    fn empty_leaderboard() {
+        assert_eq!(
+            leaderboard(&[], &config()),
+            "Nobody is working towards the role right now."
+        );
+    }
+}
@@ -0,0 +1,219 @@
+//! Reading settings from environment variables, so the bot and its tools all read them the same way
+
+use std::{env::VarError, str::FromStr};
+
+use twilight_model::id::{marker::RoleMarker, Id};
+
+use crate::{AssignConfig, Cadence, ExcludedUsers, MessageKinds, PartialCredit};
+
+impl AssignConfig {
+    /// Read what it takes to get the role from the environment, stopping with a
+    /// human-readable error if any of it is wrong.
+    ///
+    /// # Panics
+    /// If `DISCORD_ROLE` isn't set, anything can't be parsed, or `MAX_TRACKED_USERS` is 0.
+    #[must_use]
+    pub fn from_env() -> Self {
+        let role: Id<RoleMarker> = parse_var("DISCORD_ROLE");
+
+        // These values are optional, and they both have default values of 60
+        let message_requirement: u64 = get_var("MESSAGE_REQUIREMENT").unwrap_or(60);
+        let message_cooldown: u64 = get_var("MESSAGE_COOLDOWN").unwrap_or(60);
+        // If there's a window, we cap how many messages count in it instead of using the cooldown
+        let cadence =
+            get_var("MESSAGE_WINDOW").map_or(Cadence::MinGap(message_cooldown), |window| {
+                Cadence::WindowCap {
+                    window,
+                    max: parse_var("MESSAGE_WINDOW_MAX"),
+                }
+            });
+
+        // Which message types count, by discord's numbers for them. If there aren't any, count normal messages and replies.
+        let counted_kinds: Vec<u8> = get_list_var("COUNTED_MESSAGE_KINDS");
+        let counted_kinds = if counted_kinds.is_empty() {
+            MessageKinds::DEFAULT
+        } else {
+            MessageKinds::from_codes(&counted_kinds)
+                .expect("COUNTED_MESSAGE_KINDS can only contain message types below 64!")
+        };
+
+        // Given in days, because that's how people think about it, but we work in seconds
+        let min_tenure = get_var::<u64>("MIN_TENURE_DAYS")
+            .unwrap_or(0)
+            .checked_mul(24 * 60 * 60)
+            .expect("MIN_TENURE_DAYS is too big! Try a number of days that's less than a few million years.");
+
+        // With no room at all, every new user would be forgotten as soon as we saw them
+        let max_tracked_users: Option<usize> = get_var("MAX_TRACKED_USERS");
+        assert!(
+            max_tracked_users != Some(0),
+            "MAX_TRACKED_USERS must be at least 1, or unset to keep track of everyone"
+        );
+
+        Self {
+            role,
+            cadence,
+            message_requirement,
+            counted_kinds,
+            min_tenure,
+            excluded_users: ExcludedUsers::new(get_list_var("EXCLUDED_USERS")),
+            // By default, messages sent too quickly don't count at all
+            partial_credit: get_var("PARTIAL_CREDIT_PERCENT").map(|percent| PartialCredit {
+                percent,
+                max_percent: get_var("PARTIAL_CREDIT_MAX_PERCENT").unwrap_or(100),
+            }),
+            max_tracked_users,
+            streak_days: get_var("STREAK_DAYS"),
+            tracking_only: get_var("TRACKING_ONLY").unwrap_or(false),
+        }
+    }
+}
+
+/// Read a variable we can't run without.
+///
+/// # Panics
+/// With a human-readable error if it isn't set, or can't be parsed.
+#[must_use]
+pub fn parse_var<T: FromStr>(name: &str) -> T {
+    match parse_var_res(name) {
+        Ok(v) => v,
+        Err(ParseVarError::Parse(_)) => {
+            panic!("Could not parse {name} as {}!", std::any::type_name::<T>())
+        }
+        Err(ParseVarError::Var(VarError::NotPresent)) => {
+            panic!("Could not find {name} in environment!")
+        }
+        Err(ParseVarError::Var(VarError::NotUnicode(_))) => {
+            panic!("{name} does not have a unicode value!")
+        }
+    }
+}
+
+/// Read a variable that has a default, returning `None` if it isn't set.
+///
+/// # Panics
+/// With a human-readable error if it's set, but can't be parsed.
+#[must_use]
+pub fn get_var<T: FromStr>(name: &str) -> Option<T> {
+    match parse_var_res(name) {
+        Ok(v) => Some(v),
+        Err(ParseVarError::Parse(_)) => {
+            panic!("Could not parse {name} as {}!", std::any::type_name::<T>())
+        }
+        Err(ParseVarError::Var(VarError::NotPresent)) => None,
+        Err(ParseVarError::Var(VarError::NotUnicode(_))) => {
+            panic!("{name} does not have a unicode value!")
+        }
+    }
+}
+
+/// Read a comma-separated list of values, like `1,2,3`. If the variable isn't set, the list is empty.
+///
+/// # Panics
+/// With a human-readable error if any of the values can't be parsed.
+#[must_use]
+pub fn get_list_var<T: FromStr>(name: &str) -> Vec<T> {
+    get_var::<String>(name)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(|item| {
+            item.parse().unwrap_or_else(|_| {
+                panic!(
+                    "Could not parse {item} in {name} as {}!",
+                    std::any::type_name::<T>()
+                )
+            })
+        })
+        .collect()
+}
+
+/// The profile we're running as, like `staging`, if there is one
+#[must_use]
+pub fn profile() -> Option<String> {
+    std::env::var("EPHEMEROLE_PROFILE")
+        .ok()
+        .filter(|profile| !profile.is_empty())
+}
+
+// This reads a variable, looking for a version of it for our profile first.
+// With the `staging` profile, `STAGING_DISCORD_GUILD` is used over `DISCORD_GUILD` if it's set.
+fn read_var(name: &str) -> Result<String, VarError> {
+    read_profile_var(name, profile().as_deref(), |name| std::env::var(name))
+}
+
+// The same, but getting variables from `var`, so tests don't have to change the real environment
+fn read_profile_var(
+    name: &str,
+    profile: Option<&str>,
+    var: impl Fn(&str) -> Result<String, VarError>,
+) -> Result<String, VarError> {
+    if let Some(profile) = profile {
+        match var(&format!("{}_{name}", profile.to_uppercase())) {
+            Err(VarError::NotPresent) => {}
+            other => return other,
+        }
+    }
+    var(name)
+}
+
+// The bot uses "environment variables" for configuration.
+// This helps the bot pick one of them out and convert the value (which is always text) into a number.
+fn parse_var_res<T: FromStr>(name: &str) -> Result<T, ParseVarError<T>> {
+    read_var(name) // get the variable
+        .map_err(ParseVarError::Var)? // if it doesn't exist, convert the error to a ParseVarError and bail out
+        .parse() // Try to turn it into the type we want
+        .map_err(ParseVarError::Parse) // If it can't be turned into that, wrap up the error and return it
+}
+
+/// The different types of errors we can get when we try to parse a variable
+enum ParseVarError<T: FromStr> {
+    Var(VarError),
+    Parse(<T as FromStr>::Err),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    /// Read `name` as `profile` would, from an environment with just `vars` in it
+    fn read(name: &str, profile: Option<&str>, vars: &[(&str, &str)]) -> Result<String, VarError> {
+        let vars: HashMap<&str, &str> = vars.iter().copied().collect();
+        read_profile_var(name, profile, |name| {
+            vars.get(name)
+                .map(|value| (*value).to_owned())
+                .ok_or(VarError::NotPresent)
+        })
+    }
+
+    #[test]
+    fn profile_variables_take_priority() {
+        let vars = [("DISCORD_GUILD", "1"), ("STAGING_DISCORD_GUILD", "2")];
+        assert_eq!(
+            read("DISCORD_GUILD", Some("staging"), &vars),
+            Ok("2".to_owned())
+        );
+        assert_eq!(read("DISCORD_GUILD", None, &vars), Ok("1".to_owned()));
+        // Another profile doesn't see staging's variables
+        assert_eq!(
+            read("DISCORD_GUILD", Some("prod"), &vars),
+            Ok("1".to_owned())
+        );
+    }
+
+    #[test]
+    fn profile_falls_back_to_plain_variables() {
+        let vars = [("DISCORD_GUILD", "1")];
+        assert_eq!(
+            read("DISCORD_GUILD", Some("staging"), &vars),
+            Ok("1".to_owned())
+        );
+        assert_eq!(
+            read("DISCORD_ROLE", Some("staging"), &vars),
+            Err(VarError::NotPresent)
+        );
+    }
+}
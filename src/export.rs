@@ -0,0 +1,226 @@
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Error as IoError, ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{MapStats, MessageMap};
+
+/// Write the whole map out as JSON, for people to look at while debugging. It's an object
+/// of user IDs (as strings, since they're too big for some JSON readers) to their data.
+///
+/// # Errors
+/// If the writer fails.
+pub fn export_json(message_map: &MessageMap, mut writer: impl Write) -> Result<(), IoError> {
+    writer.write_all(b"{")?;
+    for (index, (user, data)) in message_map.iter().enumerate() {
+        // Every entry after the first needs a comma before it
+        if index > 0 {
+            writer.write_all(b",")?;
+        }
+        write!(
+            writer,
+            "\n  \"{user}\": {{\"messages\": {}, \"last_message_at\": {}, \"window_start\": {}, \"window_messages\": {}, \"first_seen_at\": {}, \"partial_points\": {}, \"partial_window_points\": {}, \"streak\": {}, \"last_active_day\": {}}}",
+            data.messages,
+            data.last_message_at,
+            data.window_start,
+            data.window_messages,
+            data.first_seen_at,
+            data.partial_points,
+            data.partial_window_points,
+            data.streak,
+            data.last_active_day
+        )?;
+    }
+    writer.write_all(b"\n}\n")?;
+    Ok(())
+}
+
+/// How the bot is doing, for scripts that keep an eye on it
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Status {
+    /// How many seconds the bot has been running for
+    pub uptime_seconds: u64,
+    /// Are we connected to discord right now?
+    pub gateway_connected: bool,
+    /// How many people we've given the role to since starting
+    pub grants_this_session: u64,
+    /// Everyone we're keeping track of
+    pub stats: MapStats,
+}
+
+/// Write the status out as a small JSON object
+///
+/// # Errors
+/// If the writer fails.
+pub fn export_status(status: &Status, mut writer: impl Write) -> Result<(), IoError> {
+    // JSON has `null` for values we don't have
+    let median_messages = status
+        .stats
+        .median_messages
+        .map_or_else(|| "null".to_owned(), |median| median.to_string());
+    writeln!(
+        writer,
+        "{{\"uptime_seconds\": {}, \"gateway_connected\": {}, \"grants_this_session\": {}, \"tracked_users\": {}, \"total_messages\": {}, \"granted_candidates\": {}, \"median_messages\": {median_messages}}}",
+        status.uptime_seconds,
+        status.gateway_connected,
+        status.grants_this_session,
+        status.stats.total_users,
+        status.stats.total_messages,
+        status.stats.granted_candidates
+    )
+}
+
+/// Dump the whole map to a JSON file at `path`, replacing whatever was there
+///
+/// # Errors
+/// If the file can't be written.
+pub fn dump_map(message_map: &MessageMap, path: &Path) -> Result<(), IoError> {
+    write_atomic(path, |writer| export_json(message_map, writer))
+}
+
+/// Write a file so that anyone reading it sees either the old version or the new one, never
+/// half of each. We write to a temporary file next to it, then swap it into place.
+///
+/// # Errors
+/// If the temporary file can't be written, or can't be moved into place.
+pub fn write_atomic(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> Result<(), IoError>,
+) -> Result<(), IoError> {
+    let temp_path = temp_path(path);
+    let mut writer = BufWriter::new(File::create(&temp_path)?);
+    let result = write(&mut writer)
+        .and_then(|()| writer.flush())
+        .and_then(|()| writer.get_ref().sync_all());
+    if let Err(error) = result {
+        // Don't leave half-written files lying around
+        fs::remove_file(&temp_path).ok();
+        return Err(error);
+    }
+    fs::rename(&temp_path, path)
+}
+
+/// Make sure [`write_atomic`] will be able to write to `path`, so we find out when the bot starts
+/// instead of when it's too late. If `create_dirs` is set, missing parent directories are created.
+///
+/// # Errors
+/// If `path` is a directory, its parent directory doesn't exist (and we weren't asked to create it),
+/// or we can't write a file next to it.
+pub fn check_writable(path: &Path, create_dirs: bool) -> Result<(), IoError> {
+    if path.is_dir() {
+        return Err(IoError::new(ErrorKind::InvalidInput, "it is a directory"));
+    }
+    // A path with no directory in it, like `dump.json`, goes in the current directory, which always exists
+    let parent = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty());
+    if let Some(parent) = parent {
+        if create_dirs {
+            fs::create_dir_all(parent)?;
+        } else if !parent.is_dir() {
+            return Err(IoError::new(
+                ErrorKind::NotFound,
+                "its directory doesn't exist",
+            ));
+        }
+    }
+    // The only way to really know if we can write there is to try
+    let temp_path = temp_path(path);
+    File::create(&temp_path)?;
+    fs::remove_file(&temp_path)
+}
+
+/// Where [`write_atomic`] puts the file while it's being written. It has to be in the same
+/// directory, since moving a file to a different disk isn't atomic.
+#[must_use]
+pub fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory of our own to try writing in, named after the test using it
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("ephemerole-test-{name}-{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn directories_are_not_writable() {
+        let dir = temp_dir("directory");
+        let error = check_writable(&dir, false).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_directories_are_only_created_when_asked() {
+        let dir = temp_dir("missing");
+        let path = dir.join("output").join("dump.json");
+        let error = check_writable(&path, false).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+        assert!(!dir.join("output").exists());
+
+        check_writable(&path, true).unwrap();
+        assert!(dir.join("output").is_dir());
+        // Checking doesn't leave anything behind
+        assert_eq!(fs::read_dir(dir.join("output")).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Write `status` out, and read it back as JSON
+    fn status_json(status: &Status) -> serde_json::Value {
+        let mut written = Vec::new();
+        export_status(status, &mut written).unwrap();
+        serde_json::from_slice(&written).unwrap()
+    }
+
+    #[test]
+    fn status_is_valid_json() {
+        let status = Status {
+            uptime_seconds: 3600,
+            gateway_connected: true,
+            grants_this_session: 4,
+            stats: MapStats {
+                total_users: 3,
+                total_messages: 70,
+                granted_candidates: 1,
+                median_messages: Some(20),
+                oldest_activity: Some(100),
+            },
+        };
+        let expected = serde_json::json!({
+            "uptime_seconds": 3600,
+            "gateway_connected": true,
+            "grants_this_session": 4,
+            "tracked_users": 3,
+            "total_messages": 70,
+            "granted_candidates": 1,
+            "median_messages": 20,
+        });
+        assert_eq!(status_json(&status), expected);
+    }
+
+    #[test]
+    fn status_without_a_median_is_null() {
+        let status = Status {
+            uptime_seconds: 0,
+            gateway_connected: false,
+            grants_this_session: 0,
+            stats: MapStats::default(),
+        };
+        assert_eq!(
+            status_json(&status)["median_messages"],
+            serde_json::Value::Null
+        );
+        assert_eq!(status_json(&status)["gateway_connected"], false);
+    }
+}
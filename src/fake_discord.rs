@@ -0,0 +1,53 @@
+//! Fake discords for tests to talk to, so we can see what the bot asks for and how it handles the answers
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    sync::mpsc::{channel, Receiver},
+};
+
+/// A fake discord that accepts connections, but never answers, so role additions hang
+pub fn silent_discord() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    std::thread::spawn(move || {
+        // Hold on to every connection, so none of them are closed
+        let connections: Vec<_> = listener.incoming().collect();
+        drop(connections);
+    });
+    address
+}
+
+/// A fake discord that answers one request with `status` and a JSON `body`.
+/// The request it got, headers and all, comes out of the returned receiver.
+pub fn fake_discord(status: &'static str, body: &'static str) -> (String, Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let (sender, requests) = channel();
+    std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        // Read the headers, up to the blank line after them, then whatever body they say there is
+        let mut request = String::new();
+        let mut body_length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if let Some(length) = line.to_lowercase().strip_prefix("content-length:") {
+                body_length = length.trim().parse().unwrap();
+            }
+            if line.trim().is_empty() {
+                break;
+            }
+            request.push_str(&line);
+        }
+        reader.read_exact(&mut vec![0; body_length]).unwrap();
+        let response = format!(
+            "HTTP/1.1 {status}\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        reader.get_mut().write_all(response.as_bytes()).unwrap();
+        sender.send(request).unwrap();
+    });
+    (address, requests)
+}
@@ -0,0 +1,517 @@
+//! Giving people their role in the background, so we never hold up new messages
+
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use ahash::AHashMap;
+use ephemerole::{AssignConfig, Grant, GrantLimiter, MessageMap};
+use tokio::{
+    runtime::Handle,
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+};
+use tokio_util::task::TaskTracker;
+use twilight_http::{
+    api_error::{ApiError, GeneralApiError},
+    error::ErrorType,
+    request::AuditLogReason,
+    response::StatusCode,
+    Client,
+};
+use twilight_model::id::{
+    marker::{GuildMarker, RoleMarker, UserMarker},
+    Id,
+};
+
+use crate::settings::Settings;
+
+/// What happened when we tried to give someone the role
+#[derive(Debug, Copy, Clone)]
+pub struct Finished {
+    /// Who we were giving the role to
+    pub grant: Grant,
+    /// Did they actually get it?
+    pub outcome: Outcome,
+}
+
+/// How a role addition went
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// They have the role now
+    Granted,
+    /// Something went wrong, like discord having a bad moment or us not being allowed to give out
+    /// the role, so they keep their progress and get another chance on their next message
+    TryAgain,
+    /// They aren't in the server any more, so there's nobody to give the role to
+    GaveUp,
+}
+
+/// Everything we need to give people roles in the background
+pub struct Granter {
+    client: Arc<Client>,
+    guild: Id<GuildMarker>,
+    /// What to say in the audit log when we give someone the role
+    reason_template: String,
+    /// A message to privately send users when they get the role, if we have one
+    dm_template: Option<String>,
+    /// How many role additions can be waiting at once before we stop accepting more
+    queue_capacity: usize,
+    /// How many grants we've had to put off because too many were already waiting
+    dropped: u64,
+    /// The most roles we'll give out in a window of time, if there's a limit
+    limiter: Option<GrantLimiter>,
+    /// How many grants we've had to put off because we'd already given out enough roles recently
+    deferred: u64,
+    /// How many people we've given the role to since starting
+    granted: u64,
+    /// The background tasks, so we can wait for them to finish when shutting down
+    tasks: TaskTracker,
+    /// The background runtime the tasks run on
+    runtime: Handle,
+    /// Users we're in the middle of giving the role to, so we never do it twice at once
+    in_flight: AHashMap<Id<UserMarker>, Grant>,
+    /// Where role additions report back to when they're done
+    finished: UnboundedSender<Finished>,
+}
+
+impl Granter {
+    /// Make a new granter, plus the receiving end for role additions that are done.
+    /// Everything that comes out of it needs to be handed to [`Granter::finished`].
+    pub fn new(
+        client: Arc<Client>,
+        settings: &Settings,
+        tasks: TaskTracker,
+        runtime: Handle,
+    ) -> (Self, UnboundedReceiver<Finished>) {
+        let (finished, finished_receiver) = unbounded_channel();
+        let granter = Self {
+            client,
+            guild: settings.guild,
+            reason_template: settings.grant_reason.clone(),
+            dm_template: settings.dm_template.clone(),
+            queue_capacity: settings.grant_queue_capacity,
+            dropped: 0,
+            limiter: settings.grant_limiter,
+            deferred: 0,
+            granted: 0,
+            tasks,
+            runtime,
+            in_flight: AHashMap::new(),
+            finished,
+        };
+        (granter, finished_receiver)
+    }
+
+    /// Start giving someone the role in the background. If we know their name, it's used
+    /// in logs and their private message, otherwise we fall back to their ID.
+    pub fn grant(
+        &mut self,
+        grant: Grant,
+        name: Option<&str>,
+        config: &AssignConfig,
+        message_map: &mut MessageMap,
+    ) {
+        let name = name.map_or_else(|| grant.user.to_string(), ToOwned::to_owned);
+        // If we're already giving them the role, there's no need to ask discord again.
+        // If that one doesn't work, they'll be put back in the map when it finishes.
+        if self.in_flight.contains_key(&grant.user) {
+            println!("Already giving {name} the role, not asking discord again");
+            return;
+        }
+        // If discord can't keep up with us, don't pile up role additions forever.
+        // Put the user back, so they get the role on their next message instead.
+        // Only role additions count, not the other things running in the background, like private messages.
+        if self.in_flight.len() >= self.queue_capacity {
+            self.dropped += 1;
+            eprintln!(
+                "WARN: Too many role additions waiting, putting off {name} ({} grants dropped due to backpressure)",
+                self.dropped
+            );
+            ephemerole::requeue_user(grant, config, message_map);
+            return;
+        }
+        // If we've given out too many roles recently, put the user back, so they get it on a later message instead
+        if let Some(limiter) = &mut self.limiter {
+            if !limiter.allow(now()) {
+                self.deferred += 1;
+                eprintln!(
+                    "WARN: Given out too many roles recently, putting off {name} ({} grants deferred by the cap)",
+                    self.deferred
+                );
+                ephemerole::requeue_user(grant, config, message_map);
+                return;
+            }
+        }
+        // Fill in the audit log reason and private message now, so the background task only has to send them
+        let messages = grant.messages.to_string();
+        let days = (grant.sent_at.saturating_sub(grant.first_seen_at) / (24 * 60 * 60)).to_string();
+        let reason = ephemerole::render_template(
+            &self.reason_template,
+            &[("messages", &messages), ("days", &days)],
+        );
+        // Discord won't take a reason that's too long, so cut it off rather than failing to give the role
+        let reason = reason.chars().take(MAX_REASON_LENGTH).collect();
+        let dm = self.dm_template.as_deref().map(|template| {
+            let user = format!("<@{}>", grant.user);
+            let role = format!("<@&{}>", config.role);
+            ephemerole::render_template(
+                template,
+                &[("user", &user), ("name", &name), ("role", &role)],
+            )
+        });
+        let text = Text { reason, dm };
+        self.in_flight.insert(grant.user, grant);
+        self.tasks.spawn_on(
+            add_role(
+                self.client.clone(),
+                self.guild,
+                config.role,
+                grant,
+                name,
+                text,
+                self.finished.clone(),
+            ),
+            &self.runtime,
+        );
+    }
+
+    /// Deal with a role addition that's done. If it didn't work, the user is put back in the map,
+    /// so they don't lose the messages they sent to earn it, unless they've left the server.
+    pub fn finished(
+        &mut self,
+        finished: Finished,
+        config: &AssignConfig,
+        message_map: &mut MessageMap,
+    ) {
+        let grant = finished.grant;
+        self.in_flight.remove(&grant.user);
+        match finished.outcome {
+            Outcome::Granted => self.granted += 1,
+            Outcome::TryAgain => ephemerole::requeue_user(grant, config, message_map),
+            // They've left, so there's nobody to keep track of
+            Outcome::GaveUp => {}
+        }
+    }
+
+    /// How many people we've given the role to since starting
+    pub const fn granted(&self) -> u64 {
+        self.granted
+    }
+
+    /// Give up on every role addition that hasn't finished, putting those users back in the map
+    /// so their progress isn't lost. Returns how many we gave up on.
+    pub fn abandon(&mut self, config: &AssignConfig, message_map: &mut MessageMap) -> usize {
+        let abandoned = self.in_flight.len();
+        for grant in self.in_flight.drain().map(|(_, grant)| grant) {
+            ephemerole::requeue_user(grant, config, message_map);
+        }
+        abandoned
+    }
+}
+
+/// The current time in seconds. We don't use message times for this, because old messages
+/// being counted shouldn't get around the cap.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// The longest audit log reason discord accepts, in characters
+const MAX_REASON_LENGTH: usize = 512;
+
+/// The text that goes with a role addition, filled in ahead of time
+struct Text {
+    /// What to say in the audit log
+    reason: String,
+    /// What to privately message the user, if anything
+    dm: Option<String>,
+}
+
+/// Add a role to a specific user, reporting the error in the console.
+/// When it's done, we report back through `finished`, so the user keeps their progress if it didn't work.
+/// If we have a message for them, send it to them privately once they have the role.
+async fn add_role(
+    client: Arc<Client>,
+    guild: Id<GuildMarker>,
+    role: Id<RoleMarker>,
+    grant: Grant,
+    name: String,
+    text: Text,
+    finished: UnboundedSender<Finished>,
+) {
+    let target = grant.user;
+    // Attempt to add the user's role, reporting the error if we can't
+    let result = client
+        .add_guild_member_role(guild, target, role)
+        .reason(&text.reason)
+        .await;
+    let outcome = match &result {
+        Ok(_) => Outcome::Granted,
+        Err(error) if member_left(error) => {
+            println!("Could not give {name} ({target}) the role, they left the server");
+            Outcome::GaveUp
+        }
+        Err(error) if is_transient(error) => {
+            eprintln!("ERROR: could not give {name} ({target}) the role, will try again on their next message: {error:?}");
+            Outcome::TryAgain
+        }
+        Err(error) => {
+            // Things like missing permissions need fixing in the server, and nobody should lose their progress over them
+            eprintln!("ERROR: could not give {name} ({target}) the role, check the bot's permissions and role position. Will try again on their next message: {error:?}");
+            Outcome::TryAgain
+        }
+    };
+    // This only fails if we're shutting down, in which case there's nobody to tell anyway
+    finished.send(Finished { grant, outcome }).ok();
+    if outcome != Outcome::Granted {
+        return;
+    }
+    // How long it took them, which is useful for working out if the requirement is right
+    let took = grant.sent_at.saturating_sub(grant.first_seen_at);
+    println!(
+        "Gave {name} ({target}) the role, {} hours and {} minutes after their first message",
+        took / 3600,
+        took % 3600 / 60
+    );
+    if let Some(dm) = text.dm {
+        send_dm(&client, target, &name, &dm).await;
+    }
+}
+
+/// Discord tells us someone isn't in the server any more with error code 10007
+const fn member_left(error: &twilight_http::Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorType::Response {
+            error: ApiError::General(GeneralApiError { code: 10007, .. }),
+            ..
+        }
+    )
+}
+
+/// Could asking discord again work straight away? Discord having problems, timeouts, and rate limits
+/// go away on their own. Anything else, like missing permissions or the role being above ours,
+/// needs someone to fix it first.
+fn is_transient(error: &twilight_http::Error) -> bool {
+    match error.kind() {
+        ErrorType::RequestCanceled
+        | ErrorType::RequestError
+        | ErrorType::RequestTimedOut
+        | ErrorType::RatelimiterTicket
+        | ErrorType::ServiceUnavailable { .. } => true,
+        ErrorType::Response { status, .. } => {
+            status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+        }
+        _ => false,
+    }
+}
+
+/// Privately message a user. This never fails the role grant, it just reports what went wrong.
+async fn send_dm(client: &Client, target: Id<UserMarker>, name: &str, content: &str) {
+    // Discord makes us open a private channel with the user before we can message them
+    let channel = match client.create_private_channel(target).await {
+        Ok(response) => response.model().await,
+        Err(error) => return report_dm_error(name, &error),
+    };
+    let channel = match channel {
+        Ok(channel) => channel,
+        Err(error) => {
+            eprintln!("ERROR: could not read private channel for {name}: {error:?}");
+            return;
+        }
+    };
+    if let Err(error) = client.create_message(channel.id).content(content).await {
+        report_dm_error(name, &error);
+    }
+}
+
+/// Lots of people have their DMs closed, which isn't something we need to shout about
+fn report_dm_error(name: &str, error: &twilight_http::Error) {
+    if dms_closed(error) {
+        println!("Could not message {name} about their new role, they have DMs closed");
+    } else {
+        eprintln!("ERROR: could not message {name} about their new role: {error:?}");
+    }
+}
+
+/// Discord tells us a user won't accept our messages with a 403, or error code 50007
+fn dms_closed(error: &twilight_http::Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorType::Response { status, error, .. }
+            if *status == StatusCode::FORBIDDEN
+                || matches!(error, ApiError::General(GeneralApiError { code: 50007, .. }))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use ephemerole::Bot;
+
+    use super::*;
+    use crate::fake_discord::fake_discord;
+
+    /// A granter talking to the fake discord in `settings`, running on the test's runtime
+    fn granter(settings: &Settings) -> (Granter, UnboundedReceiver<Finished>) {
+        let client = Arc::new(crate::http_client(settings));
+        Granter::new(client, settings, TaskTracker::new(), Handle::current())
+    }
+
+    /// `user` earning the role with 60 messages over 2 days
+    fn grant_for(user: u64) -> Grant {
+        Grant {
+            user: Id::new(user),
+            sent_at: 2 * 24 * 60 * 60,
+            first_seen_at: 0,
+            messages: 60,
+        }
+    }
+
+    /// Give user 5 the role through a fake discord answering with `status` and `body`.
+    /// Returns how it went, the request discord got, and the bot afterwards.
+    async fn grant_with_discord(
+        status: &'static str,
+        body: &'static str,
+    ) -> (Finished, String, Bot) {
+        let (address, requests) = fake_discord(status, body);
+        let mut settings = Settings::for_tests(address);
+        settings.grant_reason = "Sent {messages} messages in {days} days".to_owned();
+        let (mut granter, mut finished_grants) = granter(&settings);
+        let mut bot = Bot::new(settings.config.clone(), 0, 0);
+        granter.grant(grant_for(5), None, &bot.config, &mut bot.message_map);
+        let finished = finished_grants.recv().await.unwrap();
+        granter.finished(finished, &bot.config, &mut bot.message_map);
+        (finished, requests.recv().unwrap(), bot)
+    }
+
+    #[tokio::test]
+    async fn adds_role_with_reason() {
+        let (finished, request, bot) = grant_with_discord("204 No Content", "").await;
+        assert_eq!(finished.outcome, Outcome::Granted);
+        assert!(bot.message_map.is_empty());
+        // Discord only got asked once, for the right member and role, with the reason filled in
+        assert!(request.starts_with("PUT /api/v10/guilds/1/members/5/roles/2 HTTP/1.1\r\n"));
+        assert!(request
+            .to_lowercase()
+            .contains("x-audit-log-reason: sent%2060%20messages%20in%202%20days\r\n"));
+    }
+
+    #[tokio::test]
+    async fn missing_permissions_keeps_progress() {
+        let (finished, _, bot) = grant_with_discord(
+            "403 Forbidden",
+            r#"{"message": "Missing Permissions", "code": 50013}"#,
+        )
+        .await;
+        // Once the server's permissions are fixed, they should still get the role
+        assert_eq!(finished.outcome, Outcome::TryAgain);
+        assert!(bot.message_map[&Id::new(5)].has_earned(&bot.config));
+    }
+
+    #[tokio::test]
+    async fn member_who_left_is_dropped() {
+        let (finished, _, bot) = grant_with_discord(
+            "404 Not Found",
+            r#"{"message": "Unknown Member", "code": 10007}"#,
+        )
+        .await;
+        assert_eq!(finished.outcome, Outcome::GaveUp);
+        assert!(bot.message_map.is_empty());
+    }
+
+    #[tokio::test]
+    async fn qualifying_twice_grants_once() {
+        let (address, _requests) = fake_discord("204 No Content", "");
+        let settings = Settings::for_tests(address);
+        let (mut granter, mut finished_grants) = granter(&settings);
+        let mut bot = Bot::new(settings.config.clone(), 0, 0);
+        // Two messages qualify before discord has answered the first time
+        granter.grant(grant_for(5), None, &bot.config, &mut bot.message_map);
+        granter.grant(grant_for(5), None, &bot.config, &mut bot.message_map);
+        assert_eq!(granter.tasks.len(), 1);
+        let finished = finished_grants.recv().await.unwrap();
+        granter.finished(finished, &bot.config, &mut bot.message_map);
+        assert_eq!(granter.granted(), 1);
+        assert!(bot.message_map.is_empty());
+    }
+
+    #[tokio::test]
+    async fn server_error_tries_again() {
+        let (finished, _, bot) = grant_with_discord(
+            "500 Internal Server Error",
+            r#"{"message": "500: Internal Server Error", "code": 0}"#,
+        )
+        .await;
+        // Discord might be fine next time, so they're put back to get it on their next message
+        assert_eq!(finished.outcome, Outcome::TryAgain);
+        assert!(bot.message_map[&Id::new(5)].has_earned(&bot.config));
+    }
+
+    #[tokio::test]
+    async fn full_queue_puts_users_back() {
+        let (address, _requests) = fake_discord("204 No Content", "");
+        let mut settings = Settings::for_tests(address);
+        settings.grant_queue_capacity = 1;
+        let (mut granter, mut finished_grants) = granter(&settings);
+        let mut bot = Bot::new(settings.config.clone(), 0, 0);
+        granter.grant(grant_for(5), None, &bot.config, &mut bot.message_map);
+        // User 5 is still waiting on discord, so there's no room for user 6
+        granter.grant(grant_for(6), None, &bot.config, &mut bot.message_map);
+        assert_eq!(granter.dropped, 1);
+        assert!(!bot.message_map.contains_key(&Id::new(5)));
+        assert!(bot.message_map[&Id::new(6)].has_earned(&bot.config));
+        // Once user 5 is done, there's room again
+        let finished = finished_grants.recv().await.unwrap();
+        granter.finished(finished, &bot.config, &mut bot.message_map);
+        assert!(granter.in_flight.is_empty());
+    }
+
+    /// The error we get from discord answering with `status` and `body`
+    async fn discord_error(status: &'static str, body: &'static str) -> twilight_http::Error {
+        let (address, _requests) = fake_discord(status, body);
+        let client = crate::http_client(&Settings::for_tests(address));
+        client.create_private_channel(Id::new(5)).await.unwrap_err()
+    }
+
+    #[tokio::test]
+    async fn closed_dms_are_not_errors() {
+        let cannot_message = discord_error(
+            "403 Forbidden",
+            r#"{"message": "Cannot send messages to this user", "code": 50007}"#,
+        )
+        .await;
+        assert!(dms_closed(&cannot_message));
+        let forbidden = discord_error(
+            "403 Forbidden",
+            r#"{"message": "Missing Access", "code": 50001}"#,
+        )
+        .await;
+        assert!(dms_closed(&forbidden));
+        let server_error = discord_error(
+            "500 Internal Server Error",
+            r#"{"message": "500: Internal Server Error", "code": 0}"#,
+        )
+        .await;
+        assert!(!dms_closed(&server_error));
+    }
+
+    #[tokio::test]
+    async fn grants_over_the_cap_are_put_off() {
+        let (address, _requests) = fake_discord("204 No Content", "");
+        let mut settings = Settings::for_tests(address);
+        settings.grant_limiter = Some(GrantLimiter::new(3600, 1));
+        let (mut granter, _finished_grants) = granter(&settings);
+        let mut bot = Bot::new(settings.config, 0, 0);
+        for user in [5, 6, 7] {
+            granter.grant(grant_for(user), None, &bot.config, &mut bot.message_map);
+        }
+        // Only the first one gets the role for now, and the others keep their progress for later
+        assert_eq!(granter.deferred, 2);
+        assert_eq!(granter.in_flight.len(), 1);
+        assert!(!bot.message_map.contains_key(&Id::new(5)));
+        assert!(bot.message_map[&Id::new(6)].has_earned(&bot.config));
+        assert!(bot.message_map[&Id::new(7)].has_earned(&bot.config));
+    }
+}
@@ -0,0 +1,91 @@
+//! Keeping a copy of the leaderboard and stats around, so busy servers don't have to look
+//! through everyone every time someone asks for them
+
+use std::{borrow::Cow, future::pending, time::Duration};
+
+use ephemerole::{AssignConfig, MessageMap, Snapshot};
+use tokio::time::{interval, Interval};
+
+/// The leaderboard and stats, worked out every so often instead of every time they're asked for.
+/// They can be a little out of date, but never half-updated.
+pub struct LeaderboardCache {
+    /// Ticks whenever it's time to work them out again. If there isn't one, we never keep a copy.
+    interval: Option<Interval>,
+    /// How many users go on the leaderboard
+    size: usize,
+    /// What we worked out last time, if we have yet
+    cached: Option<Snapshot>,
+}
+
+impl LeaderboardCache {
+    /// Get ready to work out the top `size` users every `every`.
+    /// Without `every`, they're worked out fresh each time they're asked for.
+    pub fn new(every: Option<Duration>, size: usize) -> Self {
+        Self {
+            interval: every.map(interval),
+            size,
+            cached: None,
+        }
+    }
+
+    /// Wait until it's time to work them out again. Without an interval, this never finishes.
+    pub async fn tick(&mut self) {
+        match &mut self.interval {
+            Some(interval) => {
+                interval.tick().await;
+            }
+            None => pending().await,
+        }
+    }
+
+    /// Work out the leaderboard and stats again, and keep them until the next time
+    pub fn refresh(&mut self, message_map: &MessageMap, config: &AssignConfig) {
+        self.cached = Some(Snapshot::take(message_map, config, self.size));
+    }
+
+    /// Get the leaderboard and stats, from our copy if we have one, or from the map if we don't
+    pub fn get(&self, message_map: &MessageMap, config: &AssignConfig) -> Cow<'_, Snapshot> {
+        self.cached.as_ref().map_or_else(
+            || Cow::Owned(Snapshot::take(message_map, config, self.size)),
+            Cow::Borrowed,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ephemerole::UserData;
+    use twilight_model::id::Id;
+
+    use super::*;
+
+    /// A map where user 5 has `messages` messages
+    fn map_with(messages: u64) -> MessageMap {
+        let data = UserData {
+            messages,
+            ..UserData::first_message(0)
+        };
+        std::iter::once((Id::new(5), data)).collect()
+    }
+
+    #[tokio::test]
+    async fn serves_the_old_copy_until_refreshed() {
+        let config = AssignConfig::new(Id::new(1));
+        let mut cache = LeaderboardCache::new(Some(Duration::from_mins(1)), 10);
+        cache.refresh(&map_with(3), &config);
+
+        // The map has moved on, but we keep showing what it was when we last looked
+        let new_map = map_with(4);
+        assert_eq!(cache.get(&new_map, &config).top, [(Id::new(5), 3)]);
+        assert_eq!(cache.get(&new_map, &config).stats.total_messages, 3);
+        cache.refresh(&new_map, &config);
+        assert_eq!(cache.get(&new_map, &config).top, [(Id::new(5), 4)]);
+    }
+
+    #[test]
+    fn without_a_copy_the_map_is_used() {
+        let config = AssignConfig::new(Id::new(1));
+        let cache = LeaderboardCache::new(None, 10);
+        assert_eq!(cache.get(&map_with(4), &config).top, [(Id::new(5), 4)]);
+    }
+}
@@ -1,12 +1,30 @@
 #![warn(clippy::all, clippy::pedantic, clippy::nursery)]
+mod bot;
+mod changes;
+mod env;
+mod export;
+mod names;
 
-use std::collections::hash_map::Entry;
+use std::{
+    cmp::Reverse,
+    collections::{hash_map::Entry, BTreeMap, BinaryHeap},
+    fmt::{Display, Formatter, Result as FmtResult},
+};
 
-use ahash::AHashMap;
+use ahash::{AHashMap, AHashSet};
+pub use bot::Bot;
+pub use changes::{apply, diff, read_changes, write_changes, MapChange};
+pub use env::{get_list_var, get_var, parse_var, profile};
+pub use export::{
+    check_writable, dump_map, export_json, export_status, temp_path, write_atomic, Status,
+};
+pub use names::{display_name, NameCache};
+use twilight_gateway::EventTypeFlags;
 use twilight_model::{
-    gateway::payload::incoming::MessageCreate,
+    channel::{message::MessageType, Message},
+    gateway::{event::Event, payload::incoming::MessageCreate, Intents},
     id::{
-        marker::{RoleMarker, UserMarker},
+        marker::{ChannelMarker, MessageMarker, RoleMarker, UserMarker},
         Id,
     },
 };
@@ -18,86 +36,1411 @@ pub struct UserData {
     pub messages: u64,
     /// When was the last message at?
     pub last_message_at: u64,
+    /// When did their current window start? Only used with [`Cadence::WindowCap`].
+    pub window_start: u64,
+    /// How many of their messages have counted in their current window? Only used with [`Cadence::WindowCap`].
+    pub window_messages: u64,
+    /// When did we first count a message from them?
+    pub first_seen_at: u64,
+    /// Points from messages sent too quickly, which turn into a message once there's enough.
+    /// Only used with [`PartialCredit`].
+    pub partial_points: u64,
+    /// How many points they've gotten since their last counted message. Only used with [`PartialCredit`].
+    pub partial_window_points: u64,
+    /// How many days in a row they've had a counted message. Only used with [`AssignConfig::streak_days`].
+    pub streak: u64,
+    /// Which day their last counted message was on. Only used with [`AssignConfig::streak_days`].
+    pub last_active_day: u64,
+}
+
+/// How many seconds are in a day
+const DAY: u64 = 24 * 60 * 60;
+
+/// Which day a time is on. Discord's epoch starts at midnight UTC, so days are UTC days.
+#[must_use]
+pub const fn day_of(timestamp: u64) -> u64 {
+    timestamp / DAY
+}
+
+impl UserData {
+    /// The data for someone whose first counted message was sent at `sent_at`
+    #[must_use]
+    pub const fn first_message(sent_at: u64) -> Self {
+        Self {
+            messages: 1,
+            last_message_at: sent_at,
+            window_start: sent_at,
+            window_messages: 1,
+            first_seen_at: sent_at,
+            partial_points: 0,
+            partial_window_points: 0,
+            streak: 1,
+            last_active_day: day_of(sent_at),
+        }
+    }
+
+    /// Have they done enough to get the role under `config`? Their next counted message gives it to them.
+    #[must_use]
+    pub const fn has_earned(&self, config: &AssignConfig) -> bool {
+        match config.streak_days {
+            Some(days) => self.streak >= days,
+            None => self.messages >= config.message_requirement,
+        }
+    }
+
+    /// How far they've gotten towards the role under `config`: their streak if it takes one, or their messages if not
+    #[must_use]
+    pub const fn progress(&self, config: &AssignConfig) -> u64 {
+        match config.streak_days {
+            Some(_) => self.streak,
+            None => self.messages,
+        }
+    }
+
+    /// Keep track of a message that counted, other than adding it to their message count
+    const fn record_activity(&mut self, sent_at: u64) {
+        // Set when the message was sent as the last message from this user
+        self.last_message_at = sent_at;
+        self.window_messages += 1;
+        // Their partial credit limit starts over with each counted message
+        self.partial_window_points = 0;
+        // A message the day after their last one keeps their streak going, and any later breaks it.
+        // More messages on the same day don't make it any longer.
+        let day = day_of(sent_at);
+        if day == self.last_active_day + 1 {
+            self.streak += 1;
+        } else if day > self.last_active_day + 1 {
+            self.streak = 1;
+        }
+        self.last_active_day = day;
+    }
+}
+
+/// How many points a whole message is worth. Points are percentages of a message, so they can be whole numbers.
+pub const FULL_MESSAGE_POINTS: u64 = 100;
+
+/// Give messages sent too quickly a little credit, instead of none, so a fast conversation
+/// isn't completely ignored. The limit per cooldown still stops spam from earning the role.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PartialCredit {
+    /// How much each message sent too quickly is worth, as a percentage of a normal message
+    pub percent: u64,
+    /// The most credit messages sent too quickly can earn between two counted messages, as a percentage of a normal message
+    pub max_percent: u64,
+}
+
+/// How quickly messages are allowed to count towards the role
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cadence {
+    /// Each counted message has to be sent at least this many seconds after the last one
+    MinGap(u64),
+    /// At most `max` messages count in each window of `window` seconds. The window starts
+    /// at the first message sent after the last one ended.
+    WindowCap { window: u64, max: u64 },
+}
+
+impl Display for Cadence {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::MinGap(cooldown) => write!(f, "sent at least {cooldown} seconds apart"),
+            Self::WindowCap { window, max } => {
+                write!(f, "with at most {max} counted every {window} seconds")
+            }
+        }
+    }
 }
 
 /// This holds the configuration data for the bot, plus the client for telling
 /// discord to do something.
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct AssignConfig {
     pub role: Id<RoleMarker>,
-    pub message_cooldown: u64,
+    pub cadence: Cadence,
     pub message_requirement: u64,
+    pub counted_kinds: MessageKinds,
+    /// How many seconds someone has to have been in the server before they can get the role
+    pub min_tenure: u64,
+    /// People who never get the role from us, and who we don't keep track of at all
+    pub excluded_users: ExcludedUsers,
+    /// If this is set, messages sent too quickly still count a little
+    pub partial_credit: Option<PartialCredit>,
+    /// The most users we keep track of at once. When we hit it, the ones we heard from longest ago are forgotten.
+    pub max_tracked_users: Option<usize>,
+    /// If this is set, users need a counted message on this many days in a row, instead of a number of messages
+    pub streak_days: Option<u64>,
+    /// If this is set, we keep counting messages, but nobody gets the role until it's turned off
+    pub tracking_only: bool,
+}
+
+impl AssignConfig {
+    /// The bot's defaults for giving out `role`: 60 messages, at least 60 seconds apart,
+    /// with every optional feature turned off
+    #[must_use]
+    pub fn new(role: Id<RoleMarker>) -> Self {
+        Self {
+            role,
+            cadence: Cadence::MinGap(60),
+            message_requirement: 60,
+            counted_kinds: MessageKinds::DEFAULT,
+            min_tenure: 0,
+            excluded_users: ExcludedUsers::default(),
+            partial_credit: None,
+            max_tracked_users: None,
+            streak_days: None,
+            tracking_only: false,
+        }
+    }
+}
+
+/// A list of users to leave alone, like staff or other bots. By default, nobody is excluded.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExcludedUsers(AHashSet<Id<UserMarker>>);
+
+impl ExcludedUsers {
+    /// Exclude everyone in `users`
+    #[must_use]
+    pub fn new(users: Vec<Id<UserMarker>>) -> Self {
+        Self(users.into_iter().collect())
+    }
+
+    /// Is this user excluded?
+    #[must_use]
+    pub fn contains(&self, user: Id<UserMarker>) -> bool {
+        self.0.contains(&user)
+    }
+
+    /// How many users are excluded
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Is anybody excluded?
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Which kinds of message count towards the role. Discord also sends messages for things like
+/// people joining, boosting, or pinning, which aren't really people talking.
+///
+/// This is stored as one bit for each of discord's message type numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageKinds(u64);
+
+impl MessageKinds {
+    /// Normal messages and replies, which is what people actually send
+    pub const DEFAULT: Self = Self(1 << 0 | 1 << 19);
+
+    /// Count the message types with these numbers. Returns `None` if a number is too big
+    /// to be a message type we know how to store.
+    #[must_use]
+    pub fn from_codes(codes: &[u8]) -> Option<Self> {
+        let mut bits = 0;
+        for code in codes {
+            bits |= 1_u64.checked_shl(u32::from(*code))?;
+        }
+        Some(Self(bits))
+    }
+
+    /// Does this kind of message count?
+    #[must_use]
+    pub fn counts(self, kind: MessageType) -> bool {
+        1_u64
+            .checked_shl(u32::from(u8::from(kind)))
+            .is_some_and(|bit| self.0 & bit != 0)
+    }
+}
+
+/// Something about the config that is almost certainly a mistake
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ConfigWarning {
+    /// Every message counts, no matter how quickly they're sent
+    ZeroCooldown,
+    /// Users get the role almost as soon as they start talking
+    ZeroRequirement,
+    /// No messages after the first one ever count
+    ZeroWindowCap,
+    /// Users get the role almost as soon as they start talking, because no streak is needed
+    ZeroStreak,
+}
+
+impl Display for ConfigWarning {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::ZeroCooldown => f.write_str(
+                "the message cooldown is 0, so users can get the role by sending messages as fast as they can",
+            ),
+            Self::ZeroRequirement => f.write_str(
+                "the message requirement is 0, so users get the role almost as soon as they start talking",
+            ),
+            Self::ZeroWindowCap => f.write_str(
+                "the most messages that count in each window is 0, so nobody can ever get the role",
+            ),
+            Self::ZeroStreak => f.write_str(
+                "the streak needed is 0 days, so users get the role almost as soon as they start talking",
+            ),
+        }
+    }
+}
+
+/// A user who has earned the role and should be given it
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Grant {
+    /// Who gets the role
+    pub user: Id<UserMarker>,
+    /// When the message that earned them the role was sent
+    pub sent_at: u64,
+    /// When we first counted a message from them, so we know how long it took
+    pub first_seen_at: u64,
+    /// How many messages they had counted when they earned it
+    pub messages: u64,
 }
 
 /// This is a type alias. It is a map of user ID to user data
 pub type MessageMap = AHashMap<Id<UserMarker>, UserData>;
 
-// Convert a discord message ID to a seconds value of when it was sent relative to the discord epoch
-const fn snowflake_to_timestamp<T>(id: Id<T>) -> u64 {
+/// The newest message we've looked at in each channel. When we reconnect, discord can send
+/// us messages we've already seen, and this makes sure they aren't counted twice.
+pub type SeenMessages = AHashMap<Id<ChannelMarker>, Id<MessageMarker>>;
+
+/// Work out which intents and event types the bot needs for the features enabled in `config`.
+///
+/// If we forget to ask for an intent, discord just never sends us those events, so every
+/// feature that needs more than new server messages must add what it needs here.
+#[must_use]
+pub const fn gateway_requirements(_config: &AssignConfig) -> (Intents, EventTypeFlags) {
+    // We only care about new server messages, and don't care about message content
+    let intents = Intents::GUILD_MESSAGES;
+    // We only care about new messages, and people using our commands
+    let event_types = EventTypeFlags::MESSAGE_CREATE.union(EventTypeFlags::INTERACTION_CREATE);
+    (intents, event_types)
+}
+
+/// Check the config for values that are technically allowed, but almost never what anyone wants
+#[must_use]
+pub fn validate_config(config: &AssignConfig) -> Vec<ConfigWarning> {
+    let mut warnings = Vec::new();
+    match config.cadence {
+        // Nothing counting is a bigger problem than everything counting, so check it first
+        Cadence::WindowCap { max: 0, .. } => warnings.push(ConfigWarning::ZeroWindowCap),
+        Cadence::MinGap(0) | Cadence::WindowCap { window: 0, .. } => {
+            warnings.push(ConfigWarning::ZeroCooldown);
+        }
+        Cadence::MinGap(_) | Cadence::WindowCap { .. } => {}
+    }
+    // The message requirement is ignored when it takes a streak instead
+    match config.streak_days {
+        Some(0) => warnings.push(ConfigWarning::ZeroStreak),
+        None if config.message_requirement == 0 => warnings.push(ConfigWarning::ZeroRequirement),
+        Some(_) | None => {}
+    }
+    warnings
+}
+
+/// Convert a discord message ID to a seconds value of when it was sent relative to the discord epoch
+#[must_use]
+pub const fn snowflake_to_timestamp<T>(id: Id<T>) -> u64 {
     (id.get() >> 22) / 1000
 }
 
+/// The start of 2015, which is when discord starts counting from, in seconds since 1970
+const DISCORD_EPOCH_SECS: i64 = 1_420_070_400;
+
+/// When the sender of a message joined the server, in the same seconds as [`snowflake_to_timestamp`].
+/// Discord doesn't always tell us, so this can be `None`.
+#[must_use]
+pub fn joined_at(message_create: &MessageCreate) -> Option<u64> {
+    let joined_at = message_create.member.as_ref()?.joined_at?;
+    u64::try_from(joined_at.as_secs() - DISCORD_EPOCH_SECS).ok()
+}
+
+/// Look at an event from discord, and figure out if anybody should get a role because of it.
+///
+/// Only brand new messages count as activity. Edits ([`Event::MessageUpdate`]) are deliberately
+/// ignored, even if discord starts sending them to us, so editing a message can never count twice.
+/// Messages that aren't newer than the last one we saw in their channel are ignored too, since
+/// they're ones discord sent again after we reconnected.
+#[allow(clippy::match_same_arms)] // We want to spell out that edits are ignored on purpose
+pub fn handle_event(
+    event: &Event,
+    config: &AssignConfig,
+    message_map: &mut MessageMap,
+    seen: &mut SeenMessages,
+) -> Option<Grant> {
+    match event {
+        Event::MessageCreate(message_create) => {
+            // Message IDs go up over time, so anything that isn't newer is a repeat
+            if seen
+                .get(&message_create.channel_id)
+                .is_some_and(|newest| message_create.id <= *newest)
+            {
+                return None;
+            }
+            seen.insert(message_create.channel_id, message_create.id);
+            grant_for(message_create, config, message_map)
+        }
+        // Editing a message is not new activity, so it must never change anyone's count
+        Event::MessageUpdate(_) => None,
+        // We don't do anything with any other events
+        _ => None,
+    }
+}
+
+/// Count a batch of messages that were sent before we started, as if we'd seen them live.
+///
+/// They're counted oldest first, no matter what order they're given in.
+/// Returns everyone who earned the role from them.
+///
+/// Old messages from discord's API don't say what roles their sender has, so we can't tell
+/// if they already have the role. They don't say when their sender joined either, so with a
+/// minimum tenure we can't tell if they've been here long enough. People who earn the role from
+/// messages like that are kept track of instead, with enough messages to get it, and their next
+/// live message decides.
+pub fn backfill(
+    mut messages: Vec<Message>,
+    config: &AssignConfig,
+    message_map: &mut MessageMap,
+) -> Vec<Grant> {
+    // Message IDs go up over time, so sorting by them puts the oldest first
+    messages.sort_unstable_by_key(|message| message.id);
+    // Discord can hand us the same message twice if it was sent while we were paging through
+    messages.dedup_by_key(|message| message.id);
+    messages
+        .into_iter()
+        .filter_map(|message| {
+            let knows_enough = message
+                .member
+                .as_ref()
+                .is_some_and(|member| config.min_tenure == 0 || member.joined_at.is_some());
+            let grant = grant_for(&MessageCreate(message), config, message_map)?;
+            if knows_enough {
+                Some(grant)
+            } else {
+                requeue_user(grant, config, message_map);
+                None
+            }
+        })
+        .collect()
+}
+
+/// What happened when we looked at a message
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AssignOutcome {
+    /// They already have the role, so we ignored them
+    AlreadyHasRole,
+    /// We'd never seen them before, so we started keeping track of them
+    FirstMessage,
+    /// Their last counted message was too recent, so this one doesn't count
+    OnCooldown,
+    /// The message counted, but they haven't sent enough yet
+    Counted,
+    /// They've sent enough messages, but haven't been in the server long enough yet
+    TooNew,
+    /// Their last counted message was too recent, but this one got partial credit
+    PartialCredit,
+    /// They've sent enough messages, and should get the role. We've stopped keeping track
+    /// of them, and this is what we knew about them.
+    Grant(UserData),
+}
+
 /// Determine if the sender of a message should get a role, and track their progress
 pub fn should_assign_role(
     message_create: &MessageCreate,
-    config: AssignConfig,
+    config: &AssignConfig,
     message_map: &mut MessageMap,
 ) -> bool {
+    grant_for(message_create, config, message_map).is_some()
+}
+
+/// Track a message, and if it earned its sender the role, say who gets it
+fn grant_for(
+    message_create: &MessageCreate,
+    config: &AssignConfig,
+    message_map: &mut MessageMap,
+) -> Option<Grant> {
+    // We don't keep track of excluded users. If they were excluded after we started tracking them,
+    // forget about them now.
+    if config.excluded_users.contains(message_create.author.id) {
+        message_map.remove(&message_create.author.id);
+        return None;
+    }
+
+    // Things like join and boost messages aren't people talking, so they don't count
+    if !config.counted_kinds.counts(message_create.kind) {
+        return None;
+    }
+
     // If we know the user's roles, and we know they contain the role we'd assign
     // ignore them
-    if message_create
+    let has_role = message_create
         .member
         .as_ref()
-        .is_some_and(|v| v.roles.contains(&config.role))
-    {
-        return false;
-    }
+        .is_some_and(|v| v.roles.contains(&config.role));
 
     // When was the message created
     let message_sent_at = snowflake_to_timestamp(message_create.id);
 
+    let outcome = evaluate(
+        message_create.author.id,
+        message_sent_at,
+        has_role,
+        joined_at(message_create),
+        config,
+        message_map,
+    );
+    let AssignOutcome::Grant(data) = outcome else {
+        return None;
+    };
+    Some(Grant {
+        user: message_create.author.id,
+        sent_at: message_sent_at,
+        first_seen_at: data.first_seen_at,
+        messages: data.messages,
+    })
+}
+
+/// Track a message from `user`, sent at `sent_at`, and work out if they should get the role.
+///
+/// This is where all of the decisions are made. It doesn't know anything about discord's
+/// message types, so it can be driven with plain numbers.
+///
+/// `joined_at` is when they joined the server. If we don't know, they aren't held to the
+/// minimum tenure at all.
+pub fn evaluate(
+    user: Id<UserMarker>,
+    sent_at: u64,
+    has_role: bool,
+    joined_at: Option<u64>,
+    config: &AssignConfig,
+    message_map: &mut MessageMap,
+) -> AssignOutcome {
+    // People who already have the role don't need to earn it. If we were keeping track of them,
+    // like from old messages that didn't say what roles they had, we can stop now.
+    if has_role {
+        message_map.remove(&user);
+        return AssignOutcome::AlreadyHasRole;
+    }
+
+    // If there's no room for someone new, make some by forgetting the people we heard from longest ago.
+    // We forget a bunch at once, so we don't have to look through everyone for every new user.
+    if let Some(max) = config.max_tracked_users {
+        if message_map.len() >= max && !message_map.contains_key(&user) {
+            let batch = (max / 100).max(1);
+            evict_oldest(message_map, message_map.len() + batch - max);
+        }
+    }
+
     // This looks at the current state the user is in, if it exists. If it doesn't have a state
     // for that user, it adds one. Otherwise, we look and see if they're on cooldown and if they'd
-    // sent enough messages. Had they sent enough messages, we return `true`, which
-    // sets the return value of this function to true, as it is the last expression in the function,
-    // and it does not have a semicolon at the end.
-    match message_map.entry(message_create.author.id) {
-        Entry::Occupied(entry) => {
-            // We only do stuff to users if there has been at least message_cooldown seconds since their last message.
-            // Saturating means that if the value is too small (which it can't really be in this code), just make it as big as possible.
-            if message_sent_at.saturating_sub(entry.get().last_message_at)
-                >= config.message_cooldown
-            {
+    // sent enough messages.
+    match message_map.entry(user) {
+        Entry::Occupied(mut entry) => {
+            // Get a changeable version of their stored data
+            let data = entry.get_mut();
+            // Does this message count, or have they been sending them too quickly?
+            let counts = match config.cadence {
+                // We only do stuff to users if there has been at least `cooldown` seconds since their last message.
+                // Saturating means that if the value is too small (which it can't really be in this code), just make it as big as possible.
+                Cadence::MinGap(cooldown) => {
+                    sent_at.saturating_sub(data.last_message_at) >= cooldown
+                }
+                Cadence::WindowCap { window, max } => {
+                    // If their last window is over, this message starts a new one
+                    if sent_at.saturating_sub(data.window_start) >= window {
+                        data.window_start = sent_at;
+                        data.window_messages = 0;
+                    }
+                    data.window_messages < max
+                }
+            };
+            if counts {
+                // Have they been here long enough? If we don't know when they joined, give them the benefit of the doubt.
+                let old_enough = joined_at
+                    .is_none_or(|joined_at| sent_at.saturating_sub(joined_at) >= config.min_tenure);
                 // Have they sent enough messages? Find out today!
-                if entry.get().messages >= config.message_requirement {
-                    // We don't need to know about this user anymore. Forget about them.
-                    entry.remove();
-                    // They've sent enough messages! let the code later know that we need
-                    // to give them a role
-                    true
+                let earned = data.has_earned(config);
+                if earned && !old_enough {
+                    // They've done the talking, they just need to stick around a little longer.
+                    // Their count stays where it is, and this message still starts their cooldown.
+                    data.record_activity(sent_at);
+                    AssignOutcome::TooNew
+                } else if earned && !config.tracking_only {
+                    // They've sent enough messages! We don't need to know about this user anymore,
+                    // so forget about them, and let the code later know that we need to give them a role
+                    AssignOutcome::Grant(entry.remove())
                 } else {
-                    // Get a changeable version of their stored data
-                    let entry = entry.into_mut();
-                    // Set when the message was sent as the last message from this user
-                    entry.last_message_at = message_sent_at;
+                    // If we're only tracking, people who've earned the role keep counting like everyone else,
+                    // so they get it as soon as tracking only is turned off
+                    data.record_activity(sent_at);
                     // Increase the number of messages this user has been known to send
-                    entry.messages += 1;
-                    // The user hasn't sent enough messages, don't give them a rule
-                    false
+                    data.messages += 1;
+                    // The user hasn't sent enough messages, don't give them a role
+                    AssignOutcome::Counted
                 }
+            } else if let Some(partial) = config.partial_credit {
+                give_partial_credit(data, partial)
             } else {
                 // The user is on cooldown, don't give them a role
-                false
+                AssignOutcome::OnCooldown
             }
         }
         // if we've never seen this user, add that they've sent one message as of right now
         Entry::Vacant(entry) => {
-            entry.insert(UserData {
-                messages: 1,
-                last_message_at: message_sent_at,
-            });
+            entry.insert(UserData::first_message(sent_at));
             // The user has only sent one message; why would we give them a role?
+            AssignOutcome::FirstMessage
+        }
+    }
+}
+
+/// Give someone a little credit for a message they sent too quickly, if they haven't hit the limit
+fn give_partial_credit(data: &mut UserData, partial: PartialCredit) -> AssignOutcome {
+    let points = partial.percent.min(
+        partial
+            .max_percent
+            .saturating_sub(data.partial_window_points),
+    );
+    if points == 0 {
+        // They've had all the partial credit they can get until their next counted message
+        return AssignOutcome::OnCooldown;
+    }
+    data.partial_window_points += points;
+    data.partial_points += points;
+    // Every whole message's worth of points turns into a message
+    data.messages += data.partial_points / FULL_MESSAGE_POINTS;
+    data.partial_points %= FULL_MESSAGE_POINTS;
+    AssignOutcome::PartialCredit
+}
+
+/// Put a user who qualified for the role, but who we couldn't give it to right now, back into the map.
+///
+/// They keep enough messages (or a long enough streak) to qualify, so their next message after the
+/// cooldown gives them the role.
+pub fn requeue_user(grant: Grant, config: &AssignConfig, message_map: &mut MessageMap) {
+    let streak = config.streak_days.unwrap_or(0);
+    let entry = message_map.entry(grant.user).or_insert_with(|| UserData {
+        messages: config.message_requirement,
+        last_message_at: grant.sent_at,
+        window_start: grant.sent_at,
+        window_messages: 0,
+        first_seen_at: grant.first_seen_at,
+        partial_points: 0,
+        partial_window_points: 0,
+        streak,
+        last_active_day: day_of(grant.sent_at),
+    });
+    // If they somehow got a new entry in the meantime, don't lose the progress they made
+    entry.messages = entry.messages.max(config.message_requirement);
+    entry.streak = entry.streak.max(streak);
+}
+
+/// Forget the `count` users we heard from longest ago. If two of them were last heard from
+/// at the same time, the one with the lower ID goes first.
+pub fn evict_oldest(message_map: &mut MessageMap, count: usize) {
+    if count == 0 {
+        return;
+    }
+    if count >= message_map.len() {
+        message_map.clear();
+        return;
+    }
+    let mut activity: Vec<(u64, Id<UserMarker>)> = message_map
+        .iter()
+        .map(|(user, data)| (data.last_message_at, *user))
+        .collect();
+    // This puts the `count` oldest at the front, without sorting everyone
+    activity.select_nth_unstable(count - 1);
+    for (_, user) in &activity[..count] {
+        message_map.remove(user);
+    }
+}
+
+/// Everyone who has already sent enough messages to get the role under `config`, sorted by ID.
+///
+/// Normally people are checked when they send a message, so this is for when the requirement
+/// goes down and people who were already past it shouldn't have to wait for their next one.
+#[must_use]
+pub fn recompute_eligible(message_map: &MessageMap, config: &AssignConfig) -> Vec<Id<UserMarker>> {
+    let mut eligible: Vec<Id<UserMarker>> = message_map
+        .iter()
+        .filter(|(_, data)| data.has_earned(config))
+        .map(|(user, _)| *user)
+        .collect();
+    eligible.sort_unstable();
+    eligible
+}
+
+/// Caps how many roles we give out in each window of time, no matter how many people earn them.
+///
+/// During a raid, lots of new accounts can cross a low requirement at once, and this stops us
+/// from handing all of them the role in one go.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct GrantLimiter {
+    /// How long each window lasts, in seconds
+    window: u64,
+    /// How many roles we can give out in each window
+    max: u64,
+    /// When the current window started
+    window_start: u64,
+    /// How many roles we've given out in the current window
+    granted: u64,
+}
+
+impl GrantLimiter {
+    /// Allow at most `max` roles to be given out every `window` seconds
+    #[must_use]
+    pub const fn new(window: u64, max: u64) -> Self {
+        Self {
+            window,
+            max,
+            window_start: 0,
+            granted: 0,
+        }
+    }
+
+    /// Can we give someone the role at `now`? If we can, it counts against the cap.
+    pub const fn allow(&mut self, now: u64) -> bool {
+        // If the last window is over, start a new one
+        if now.saturating_sub(self.window_start) >= self.window {
+            self.window_start = now;
+            self.granted = 0;
+        }
+        if self.granted < self.max {
+            self.granted += 1;
+            true
+        } else {
             false
         }
     }
 }
+
+/// Fill in a message template, replacing every `{name}` with the value given for `name`.
+///
+/// Placeholders we don't have a value for are left alone, so typos show up in the output.
+#[must_use]
+pub fn render_template(template: &str, values: &[(&str, &str)]) -> String {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+    // Copy everything up to the next `{`, then see if it starts a placeholder we know about
+    while let Some(start) = rest.find('{') {
+        output.push_str(&rest[..start]);
+        let placeholder = &rest[start..];
+        let replacement = placeholder.find('}').and_then(|end| {
+            let name = &placeholder[1..end];
+            values
+                .iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| (*value, end))
+        });
+        if let Some((value, end)) = replacement {
+            output.push_str(value);
+            rest = &placeholder[end + 1..];
+        } else {
+            // Not a placeholder, just a brace. Keep it and carry on after it.
+            output.push('{');
+            rest = &placeholder[1..];
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+/// Find the `n` users closest to getting the role under `config`, closest first. That's the longest
+/// streaks if it takes one, or the most messages if not. Ties go to whoever has the lower ID.
+#[must_use]
+pub fn top_n(
+    message_map: &MessageMap,
+    config: &AssignConfig,
+    n: usize,
+) -> Vec<(Id<UserMarker>, u64)> {
+    // Keep the best `n` users we've seen so far, with the worst of them on top of the heap,
+    // so we can throw them out when we find someone better. This is much quicker than
+    // sorting everyone when there are lots of users.
+    let mut best = BinaryHeap::with_capacity(n.min(message_map.len()) + 1);
+    for (user, data) in message_map {
+        // Reversing the user ID makes lower IDs count as "more" when their progress ties
+        best.push(Reverse((data.progress(config), Reverse(*user))));
+        if best.len() > n {
+            best.pop();
+        }
+    }
+    // Sorting reversed items puts whoever is furthest along first
+    best.into_sorted_vec()
+        .into_iter()
+        .map(|Reverse((progress, Reverse(user)))| (user, progress))
+        .collect()
+}
+
+/// A summary of everyone we're keeping track of
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct MapStats {
+    /// How many users we're keeping track of
+    pub total_users: usize,
+    /// How many messages they've sent, all added together
+    pub total_messages: u64,
+    /// How many users have sent enough messages, and will get the role on their next counted message
+    pub granted_candidates: usize,
+    /// The message count in the middle, if we're keeping track of anyone
+    pub median_messages: Option<u64>,
+    /// When the user we've heard from longest ago sent their last message
+    pub oldest_activity: Option<u64>,
+}
+
+/// Work out everything in [`MapStats`] by looking at each user just once, since there can be lots of them
+#[must_use]
+pub fn stats(message_map: &MessageMap, config: &AssignConfig) -> MapStats {
+    let mut stats = MapStats {
+        total_users: message_map.len(),
+        ..MapStats::default()
+    };
+    // How many users have each message count. There are only ever a few different counts,
+    // so this stays small even when there are lots of users.
+    let mut counts: BTreeMap<u64, usize> = BTreeMap::new();
+    for data in message_map.values() {
+        stats.total_messages += data.messages;
+        if data.has_earned(config) {
+            stats.granted_candidates += 1;
+        }
+        stats.oldest_activity = Some(
+            stats
+                .oldest_activity
+                .map_or(data.last_message_at, |oldest| {
+                    oldest.min(data.last_message_at)
+                }),
+        );
+        *counts.entry(data.messages).or_default() += 1;
+    }
+    // Walk up through the counts until we've passed half the users
+    let middle = stats.total_users / 2;
+    let mut seen = 0;
+    for (messages, users) in counts {
+        seen += users;
+        if seen > middle {
+            stats.median_messages = Some(messages);
+            break;
+        }
+    }
+    stats
+}
+
+/// The leaderboard and [`MapStats`] at one moment, so they can be shown again and again
+/// without looking through everyone each time
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    /// The users closest to getting the role, like [`top_n`] gives
+    pub top: Vec<(Id<UserMarker>, u64)>,
+    /// A summary of everyone, like [`stats`] gives
+    pub stats: MapStats,
+}
+
+impl Snapshot {
+    /// Look at everyone in the map, keeping the top `n` users
+    #[must_use]
+    pub fn take(message_map: &MessageMap, config: &AssignConfig, n: usize) -> Self {
+        Self {
+            top: top_n(message_map, config, n),
+            stats: stats(message_map, config),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use twilight_model::gateway::payload::incoming::MessageUpdate;
+
+    use super::*;
+
+    /// The bot's default config: 60 messages, at least 60 seconds apart
+    fn config() -> AssignConfig {
+        AssignConfig::new(Id::new(1))
+    }
+
+    #[test]
+    fn excluded_users() {
+        let excluded = ExcludedUsers::new(vec![Id::new(3), Id::new(1), Id::new(3)]);
+        assert_eq!(excluded.len(), 2);
+        assert!(excluded.contains(Id::new(1)));
+        assert!(excluded.contains(Id::new(3)));
+        assert!(!excluded.contains(Id::new(2)));
+        assert!(config().excluded_users.is_empty());
+    }
+
+    #[test]
+    fn excluded_users_are_never_tracked() {
+        let config = AssignConfig {
+            message_requirement: 1,
+            excluded_users: ExcludedUsers::new(vec![Id::new(5)]),
+            ..config()
+        };
+        let mut map = MessageMap::new();
+        for sent_at in [0, 60, 120] {
+            let message = MessageCreate(message(sent_at, 5, None));
+            assert!(!should_assign_role(&message, &config, &mut map));
+        }
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn excluded_users_are_forgotten_on_their_next_message() {
+        let mut config = config();
+        let mut map = MessageMap::new();
+        should_assign_role(&MessageCreate(message(0, 5, None)), &config, &mut map);
+        should_assign_role(&MessageCreate(message(0, 6, None)), &config, &mut map);
+        assert_eq!(map.len(), 2);
+        // Excluding someone doesn't touch the map, until they talk again
+        config.excluded_users = ExcludedUsers::new(vec![Id::new(5)]);
+        should_assign_role(&MessageCreate(message(60, 5, None)), &config, &mut map);
+        assert!(!map.contains_key(&Id::new(5)));
+        assert_eq!(map[&Id::new(6)].messages, 1);
+    }
+
+    #[test]
+    fn gateway_requirements_for_every_feature() {
+        // None of our features need more than new messages and commands yet. If one ever does,
+        // this should start failing until the intents it needs are added.
+        let expected = (
+            Intents::GUILD_MESSAGES,
+            EventTypeFlags::MESSAGE_CREATE | EventTypeFlags::INTERACTION_CREATE,
+        );
+        let configs = [
+            config(),
+            AssignConfig {
+                cadence: Cadence::WindowCap {
+                    window: 3600,
+                    max: 10,
+                },
+                min_tenure: 1000,
+                excluded_users: ExcludedUsers::new(vec![Id::new(2)]),
+                partial_credit: Some(PartialCredit {
+                    percent: 25,
+                    max_percent: 100,
+                }),
+                max_tracked_users: Some(10),
+                streak_days: Some(3),
+                tracking_only: true,
+                ..config()
+            },
+        ];
+        for config in configs {
+            assert_eq!(gateway_requirements(&config), expected);
+        }
+    }
+
+    /// A map where each user has the given number of messages, with IDs starting at 1
+    fn map_with_messages(messages: &[u64]) -> MessageMap {
+        (1..)
+            .zip(messages)
+            .map(|(user, messages)| {
+                let data = UserData {
+                    messages: *messages,
+                    ..UserData::first_message(0)
+                };
+                (Id::new(user), data)
+            })
+            .collect()
+    }
+
+    /// Discord's ID for a message sent `sent_at` seconds after its epoch
+    fn message_id(sent_at: u64) -> u64 {
+        ((sent_at * 1000) << 22) | 1
+    }
+
+    /// A normal message from `author`, sent at `sent_at`. If `roles` is set, discord told us what roles they have.
+    fn message(sent_at: u64, author: u64, roles: Option<&[u64]>) -> Message {
+        let member = roles.map(|roles| {
+            serde_json::json!({
+                "deaf": false,
+                "mute": false,
+                "flags": 0,
+                "joined_at": null,
+                "roles": roles.iter().map(u64::to_string).collect::<Vec<_>>(),
+            })
+        });
+        serde_json::from_value(serde_json::json!({
+            "id": message_id(sent_at).to_string(),
+            "channel_id": "1",
+            "author": { "id": author.to_string(), "username": "someone", "discriminator": "0", "avatar": null },
+            "member": member,
+            "content": "",
+            "timestamp": "2020-01-01T00:00:00+00:00",
+            "edited_timestamp": null,
+            "tts": false,
+            "mention_everyone": false,
+            "mentions": [],
+            "mention_roles": [],
+            "attachments": [],
+            "embeds": [],
+            "pinned": false,
+            "type": 0,
+        }))
+        .unwrap()
+    }
+
+    /// Discord telling us about a new message from `author`, sent at `sent_at`
+    pub fn created(sent_at: u64, author: u64) -> Event {
+        Event::MessageCreate(Box::new(MessageCreate(message(sent_at, author, None))))
+    }
+
+    #[test]
+    fn edits_are_ignored() {
+        let config = config();
+        let mut map = MessageMap::new();
+        let mut seen = SeenMessages::new();
+        handle_event(&created(0, 5), &config, &mut map, &mut seen);
+        let before = (map.clone(), seen.clone());
+        // An edit to a message we haven't seen, long enough after the first to count if it were new
+        let edit = Event::MessageUpdate(Box::new(MessageUpdate(message(120, 5, None))));
+        assert_eq!(handle_event(&edit, &config, &mut map, &mut seen), None);
+        assert_eq!((map, seen), before);
+    }
+
+    #[test]
+    fn repeated_messages_are_ignored() {
+        let config = config();
+        let mut map = MessageMap::new();
+        let mut seen = SeenMessages::new();
+        handle_event(&created(100, 5), &config, &mut map, &mut seen);
+        let before = map.clone();
+        // The same message again, and an older one, as discord sends after reconnecting
+        handle_event(&created(100, 5), &config, &mut map, &mut seen);
+        handle_event(&created(0, 5), &config, &mut map, &mut seen);
+        assert_eq!(map, before);
+        // An older message in another channel is new to us, so it still counts
+        let mut elsewhere = message(0, 6, None);
+        elsewhere.channel_id = Id::new(2);
+        let elsewhere = Event::MessageCreate(Box::new(MessageCreate(elsewhere)));
+        handle_event(&elsewhere, &config, &mut map, &mut seen);
+        assert_eq!(map[&Id::new(6)].messages, 1);
+        assert_eq!(seen[&Id::new(1)], Id::new(message_id(100)));
+        assert_eq!(seen[&Id::new(2)], Id::new(message_id(0)));
+    }
+
+    #[test]
+    fn only_counted_kinds_make_progress() {
+        let config = config();
+        let mut map = MessageMap::new();
+        let mut seen = SeenMessages::new();
+        // Joining and boosting are discord talking, not the user
+        for (sent_at, kind) in [(0, MessageType::UserJoin), (60, MessageType::GuildBoost)] {
+            let mut system = message(sent_at, 5, None);
+            system.kind = kind;
+            let event = Event::MessageCreate(Box::new(MessageCreate(system)));
+            handle_event(&event, &config, &mut map, &mut seen);
+        }
+        assert!(map.is_empty());
+        let mut reply = message(120, 5, None);
+        reply.kind = MessageType::Reply;
+        let event = Event::MessageCreate(Box::new(MessageCreate(reply)));
+        handle_event(&event, &config, &mut map, &mut seen);
+        assert_eq!(map[&Id::new(5)].messages, 1);
+    }
+
+    #[test]
+    fn backfill_without_roles_waits_for_a_live_message() {
+        let mut config = config();
+        config.message_requirement = 2;
+        let mut map = MessageMap::new();
+        let history = vec![
+            message(0, 5, None),
+            message(60, 5, None),
+            message(120, 5, None),
+        ];
+        assert!(backfill(history, &config, &mut map).is_empty());
+        // They're still tracked, with enough messages to get the role on their next one
+        assert!(map[&Id::new(5)].has_earned(&config));
+    }
+
+    #[test]
+    fn backfill_with_roles_grants() {
+        let mut config = config();
+        config.message_requirement = 2;
+        let mut map = MessageMap::new();
+        let history = vec![
+            message(0, 5, Some(&[])),
+            message(60, 5, Some(&[])),
+            message(120, 5, Some(&[])),
+        ];
+        let grants = backfill(history, &config, &mut map);
+        assert_eq!(grants.len(), 1);
+        assert_eq!(grants[0].user, Id::new(5));
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn backfill_without_join_time_waits_for_tenure() {
+        let mut config = config();
+        config.message_requirement = 2;
+        config.min_tenure = 1000;
+        let mut map = MessageMap::new();
+        let history = vec![
+            message(0, 5, Some(&[])),
+            message(60, 5, Some(&[])),
+            message(120, 5, Some(&[])),
+        ];
+        assert!(backfill(history, &config, &mut map).is_empty());
+        assert!(map[&Id::new(5)].has_earned(&config));
+    }
+
+    #[test]
+    fn having_the_role_stops_tracking() {
+        let config = config();
+        let mut map = MessageMap::new();
+        evaluate(Id::new(5), 0, false, None, &config, &mut map);
+        assert!(map.contains_key(&Id::new(5)));
+        let outcome = evaluate(Id::new(5), 100, true, None, &config, &mut map);
+        assert_eq!(outcome, AssignOutcome::AlreadyHasRole);
+        assert!(map.is_empty());
+    }
+
+    /// Send messages from user 1 at each of these times, and say what happened to the last one
+    fn send(times: &[u64], config: &AssignConfig, map: &mut MessageMap) -> AssignOutcome {
+        let mut outcome = AssignOutcome::AlreadyHasRole;
+        for sent_at in times {
+            outcome = evaluate(Id::new(1), *sent_at, false, None, config, map);
+        }
+        outcome
+    }
+
+    #[test]
+    fn first_message_starts_tracking() {
+        let mut map = MessageMap::new();
+        assert_eq!(
+            send(&[100], &config(), &mut map),
+            AssignOutcome::FirstMessage
+        );
+        assert_eq!(map[&Id::new(1)], UserData::first_message(100));
+    }
+
+    #[test]
+    fn cooldown_boundary() {
+        let config = config();
+        let mut map = MessageMap::new();
+        // 59 seconds later is too soon, and doesn't restart the cooldown
+        assert_eq!(send(&[0, 59], &config, &mut map), AssignOutcome::OnCooldown);
+        assert_eq!(map[&Id::new(1)].messages, 1);
+        // 60 seconds after the first one is just enough
+        assert_eq!(send(&[60], &config, &mut map), AssignOutcome::Counted);
+        assert_eq!(map[&Id::new(1)].messages, 2);
+        assert_eq!(map[&Id::new(1)].last_message_at, 60);
+    }
+
+    #[test]
+    fn grants_once_requirement_is_met() {
+        let mut config = config();
+        config.message_requirement = 3;
+        let mut map = MessageMap::new();
+        assert_eq!(
+            send(&[0, 60, 120], &config, &mut map),
+            AssignOutcome::Counted
+        );
+        let AssignOutcome::Grant(data) = send(&[180], &config, &mut map) else {
+            panic!("expected a grant");
+        };
+        assert_eq!(data.messages, 3);
+        assert_eq!(data.first_seen_at, 0);
+        assert!(map.is_empty());
+    }
+
+    /// The default config, except it takes a message on 3 days in a row
+    fn streak_config() -> AssignConfig {
+        AssignConfig {
+            streak_days: Some(3),
+            ..config()
+        }
+    }
+
+    #[test]
+    fn streak_maintained() {
+        let config = streak_config();
+        let mut map = MessageMap::new();
+        assert_eq!(
+            send(&[0, DAY, 2 * DAY], &config, &mut map),
+            AssignOutcome::Counted
+        );
+        assert_eq!(map[&Id::new(1)].streak, 3);
+        // Three messages is nowhere near the message requirement, but the streak is all that matters
+        let outcome = send(&[2 * DAY + 60], &config, &mut map);
+        assert!(matches!(outcome, AssignOutcome::Grant(_)));
+    }
+
+    #[test]
+    fn streak_broken() {
+        let config = streak_config();
+        let mut map = MessageMap::new();
+        // Skipping a day starts them over
+        assert_eq!(
+            send(&[0, DAY, 3 * DAY], &config, &mut map),
+            AssignOutcome::Counted
+        );
+        assert_eq!(map[&Id::new(1)].streak, 1);
+        assert_eq!(send(&[4 * DAY], &config, &mut map), AssignOutcome::Counted);
+        assert_eq!(map[&Id::new(1)].streak, 2);
+    }
+
+    #[test]
+    fn streak_same_day() {
+        let config = streak_config();
+        let mut map = MessageMap::new();
+        // More messages on the same day count as messages, but not towards the streak
+        assert_eq!(
+            send(&[0, 60, 120], &config, &mut map),
+            AssignOutcome::Counted
+        );
+        assert_eq!(map[&Id::new(1)].messages, 3);
+        assert_eq!(map[&Id::new(1)].streak, 1);
+        assert_eq!(send(&[DAY], &config, &mut map), AssignOutcome::Counted);
+        assert_eq!(map[&Id::new(1)].streak, 2);
+    }
+
+    #[test]
+    fn too_new_until_tenure_is_met() {
+        let mut config = config();
+        config.message_requirement = 1;
+        config.min_tenure = 1000;
+        let mut map = MessageMap::new();
+        let user = Id::new(1);
+        evaluate(user, 0, false, Some(0), &config, &mut map);
+        let outcome = evaluate(user, 500, false, Some(0), &config, &mut map);
+        assert_eq!(outcome, AssignOutcome::TooNew);
+        // Their count stays the same, but the cooldown starts again
+        assert_eq!(map[&user].messages, 1);
+        assert_eq!(map[&user].last_message_at, 500);
+        let outcome = evaluate(user, 1000, false, Some(0), &config, &mut map);
+        assert!(matches!(outcome, AssignOutcome::Grant(_)));
+        // Without knowing when they joined, they aren't held to the tenure
+        evaluate(user, 0, false, None, &config, &mut map);
+        let outcome = evaluate(user, 60, false, None, &config, &mut map);
+        assert!(matches!(outcome, AssignOutcome::Grant(_)));
+    }
+
+    #[test]
+    fn partial_credit_adds_up() {
+        let mut config = config();
+        config.partial_credit = Some(PartialCredit {
+            percent: 40,
+            max_percent: 100,
+        });
+        let mut map = MessageMap::new();
+        let outcome = send(&[0, 1, 2], &config, &mut map);
+        assert_eq!(outcome, AssignOutcome::PartialCredit);
+        // 80 points so far, and the next 20 fill up a whole message
+        assert_eq!(map[&Id::new(1)].messages, 1);
+        assert_eq!(send(&[3], &config, &mut map), AssignOutcome::PartialCredit);
+        assert_eq!(map[&Id::new(1)].messages, 2);
+        assert_eq!(map[&Id::new(1)].partial_points, 0);
+        // That's all the partial credit they get until their next counted message
+        assert_eq!(send(&[4], &config, &mut map), AssignOutcome::OnCooldown);
+        assert_eq!(send(&[60], &config, &mut map), AssignOutcome::Counted);
+        assert_eq!(send(&[61], &config, &mut map), AssignOutcome::PartialCredit);
+    }
+
+    #[test]
+    fn partial_credit_stops_at_its_limit() {
+        let mut config = config();
+        config.partial_credit = Some(PartialCredit {
+            percent: 30,
+            max_percent: 50,
+        });
+        let mut map = MessageMap::new();
+        // The second quick message only gets the 20 points left under the limit
+        let outcome = send(&[0, 1, 2], &config, &mut map);
+        assert_eq!(outcome, AssignOutcome::PartialCredit);
+        assert_eq!(map[&Id::new(1)].partial_points, 50);
+        assert_eq!(send(&[3], &config, &mut map), AssignOutcome::OnCooldown);
+        // Points left over from before a counted message are kept, and the limit starts over
+        assert_eq!(send(&[60], &config, &mut map), AssignOutcome::Counted);
+        assert_eq!(
+            send(&[61, 62], &config, &mut map),
+            AssignOutcome::PartialCredit
+        );
+        assert_eq!(map[&Id::new(1)].messages, 3);
+        assert_eq!(map[&Id::new(1)].partial_points, 0);
+    }
+
+    #[test]
+    fn window_rolls_over_at_its_end() {
+        let mut config = config();
+        config.cadence = Cadence::WindowCap {
+            window: 100,
+            max: 2,
+        };
+        let mut map = MessageMap::new();
+        // The first message starts the window, and one more fits in it
+        assert_eq!(send(&[0, 1], &config, &mut map), AssignOutcome::Counted);
+        assert_eq!(send(&[99], &config, &mut map), AssignOutcome::OnCooldown);
+        assert_eq!(map[&Id::new(1)].messages, 2);
+        // Right at the end of the window, a new one starts
+        assert_eq!(send(&[100], &config, &mut map), AssignOutcome::Counted);
+        assert_eq!(map[&Id::new(1)].window_start, 100);
+        assert_eq!(map[&Id::new(1)].window_messages, 1);
+        assert_eq!(send(&[150], &config, &mut map), AssignOutcome::Counted);
+        assert_eq!(send(&[199], &config, &mut map), AssignOutcome::OnCooldown);
+        assert_eq!(map[&Id::new(1)].messages, 4);
+    }
+
+    #[test]
+    fn config_warnings() {
+        let mut config = config();
+        assert!(validate_config(&config).is_empty());
+        config.cadence = Cadence::MinGap(0);
+        assert_eq!(validate_config(&config), [ConfigWarning::ZeroCooldown]);
+        config.cadence = Cadence::WindowCap { window: 0, max: 5 };
+        assert_eq!(validate_config(&config), [ConfigWarning::ZeroCooldown]);
+        config.cadence = Cadence::WindowCap { window: 0, max: 0 };
+        assert_eq!(validate_config(&config), [ConfigWarning::ZeroWindowCap]);
+        config.cadence = Cadence::WindowCap { window: 60, max: 0 };
+        config.message_requirement = 0;
+        assert_eq!(
+            validate_config(&config),
+            [ConfigWarning::ZeroWindowCap, ConfigWarning::ZeroRequirement]
+        );
+        // With a streak, the message requirement doesn't matter, but the streak does
+        let mut config = streak_config();
+        config.message_requirement = 0;
+        assert!(validate_config(&config).is_empty());
+        config.streak_days = Some(0);
+        assert_eq!(validate_config(&config), [ConfigWarning::ZeroStreak]);
+    }
+
+    #[test]
+    fn grant_limiter_caps_each_window() {
+        let mut limiter = GrantLimiter::new(60, 2);
+        assert!(limiter.allow(1000));
+        assert!(limiter.allow(1010));
+        assert!(!limiter.allow(1059));
+        // A new window starts once the last one is over
+        assert!(limiter.allow(1060));
+        assert!(limiter.allow(1061));
+        assert!(!limiter.allow(1062));
+    }
+
+    #[test]
+    fn render_template_fills_in_placeholders() {
+        let values = [("user", "<@5>"), ("messages", "60")];
+        assert_eq!(
+            render_template("{user} sent {messages} messages", &values),
+            "<@5> sent 60 messages"
+        );
+        // Unknown placeholders and stray braces are kept as they are
+        assert_eq!(
+            render_template("{usr} {messages", &values),
+            "{usr} {messages"
+        );
+        assert_eq!(render_template("{{user}}", &values), "{<@5>}");
+        // Values aren't filled in again, even if they look like placeholders
+        assert_eq!(render_template("{user}", &[("user", "{user}")]), "{user}");
+    }
+
+    #[test]
+    fn top_n_breaks_ties_by_lower_id() {
+        let map = map_with_messages(&[5, 9, 9, 2, 9]);
+        let top = top_n(&map, &config(), 3);
+        assert_eq!(top, [(Id::new(2), 9), (Id::new(3), 9), (Id::new(5), 9)]);
+    }
+
+    #[test]
+    fn top_n_with_fewer_users_than_asked_for() {
+        let map = map_with_messages(&[1, 3, 2]);
+        let top = top_n(&map, &config(), 10);
+        assert_eq!(top, [(Id::new(2), 3), (Id::new(3), 2), (Id::new(1), 1)]);
+        assert!(top_n(&map, &config(), 0).is_empty());
+        assert!(top_n(&MessageMap::new(), &config(), 10).is_empty());
+    }
+
+    #[test]
+    fn stats_for_a_small_map() {
+        let mut config = config();
+        config.message_requirement = 5;
+        let mut map = map_with_messages(&[1, 9, 3, 5]);
+        map.get_mut(&Id::new(2)).unwrap().last_message_at = 300;
+        map.get_mut(&Id::new(3)).unwrap().last_message_at = 100;
+        map.get_mut(&Id::new(4)).unwrap().last_message_at = 200;
+        map.get_mut(&Id::new(1)).unwrap().last_message_at = 400;
+        let expected = MapStats {
+            total_users: 4,
+            total_messages: 18,
+            granted_candidates: 2,
+            // With an even number of users, it's the upper of the two in the middle
+            median_messages: Some(5),
+            oldest_activity: Some(100),
+        };
+        assert_eq!(stats(&map, &config), expected);
+        map.remove(&Id::new(4));
+        let stats = stats(&map, &config);
+        assert_eq!(stats.median_messages, Some(3));
+        assert_eq!(stats.granted_candidates, 1);
+    }
+
+    #[test]
+    fn stats_for_an_empty_map() {
+        assert_eq!(stats(&MessageMap::new(), &config()), MapStats::default());
+    }
+
+    #[test]
+    fn top_n_ranks_by_streak() {
+        let mut map = map_with_messages(&[9, 1]);
+        map.get_mut(&Id::new(2)).unwrap().streak = 4;
+        let top = top_n(&map, &streak_config(), 10);
+        assert_eq!(top, [(Id::new(2), 4), (Id::new(1), 1)]);
+    }
+
+    #[test]
+    fn evicts_the_oldest_first() {
+        let mut map = map_with_messages(&[1, 1, 1, 1]);
+        for (user, last_message_at) in [(1, 300), (2, 100), (3, 200), (4, 100)] {
+            map.get_mut(&Id::new(user)).unwrap().last_message_at = last_message_at;
+        }
+        evict_oldest(&mut map, 0);
+        assert_eq!(map.len(), 4);
+        // Users 2 and 4 were heard from at the same time, so the lower ID goes first
+        evict_oldest(&mut map, 1);
+        assert!(!map.contains_key(&Id::new(2)));
+        evict_oldest(&mut map, 2);
+        assert_eq!(map.keys().collect::<Vec<_>>(), [&Id::new(1)]);
+        // Asking for more than there are forgets everyone
+        evict_oldest(&mut map, 5);
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn new_users_past_the_cap_push_out_the_oldest() {
+        let config = AssignConfig {
+            max_tracked_users: Some(3),
+            ..config()
+        };
+        let mut map = MessageMap::new();
+        for user in 1..=3 {
+            evaluate(Id::new(user), user * 10, false, None, &config, &mut map);
+        }
+        // Someone we already know about doesn't push anyone out
+        evaluate(Id::new(1), 100, false, None, &config, &mut map);
+        assert_eq!(map.len(), 3);
+        // User 2 was heard from longest ago now, so they make way for user 4
+        evaluate(Id::new(4), 110, false, None, &config, &mut map);
+        assert_eq!(map.len(), 3);
+        assert!(!map.contains_key(&Id::new(2)));
+        assert_eq!(map[&Id::new(4)], UserData::first_message(110));
+    }
+
+    #[test]
+    fn raising_requirement_makes_nobody_eligible() {
+        let map = map_with_messages(&[5, 20, 59]);
+        assert!(recompute_eligible(&map, &config()).is_empty());
+        let mut config = config();
+        config.message_requirement = 100;
+        assert!(recompute_eligible(&map, &config).is_empty());
+    }
+
+    #[test]
+    fn lowering_requirement_makes_users_past_it_eligible() {
+        let map = map_with_messages(&[5, 20, 3, 12, 9]);
+        let mut config = config();
+        config.message_requirement = 9;
+        assert_eq!(
+            recompute_eligible(&map, &config),
+            [Id::new(2), Id::new(4), Id::new(5)]
+        );
+    }
+}
@@ -1,97 +1,125 @@
 #![warn(clippy::all, clippy::pedantic, clippy::nursery)]
+mod backfill;
+mod commands;
+#[cfg(test)]
+mod fake_discord;
+mod grant;
+mod leaderboard;
+mod settings;
+mod status;
+
 use std::{
-    env::VarError,
-    str::FromStr,
+    future::Future,
+    path::Path,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
+    thread::JoinHandle,
 };
 
-use ephemerole::{AssignConfig, MessageMap};
-use tokio::runtime::Builder as RuntimeBuilder;
-use tokio_util::task::TaskTracker;
-use twilight_gateway::{EventTypeFlags, Shard, StreamExt};
-use twilight_http::{request::AuditLogReason, Client};
-use twilight_model::{
-    gateway::{event::Event, CloseFrame, Intents, ShardId},
-    id::{
-        marker::{GuildMarker, RoleMarker, UserMarker},
-        Id,
+use ephemerole::{AssignConfig, Bot, MessageMap};
+use grant::{Finished, Granter, Outcome};
+use leaderboard::LeaderboardCache;
+use settings::Settings;
+use status::StatusFile;
+use tokio::{
+    runtime::{Builder as RuntimeBuilder, Handle, Runtime},
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        oneshot,
     },
+    time::timeout,
 };
+use tokio_util::task::TaskTracker;
+use twilight_gateway::{Shard, StreamExt};
+use twilight_http::Client;
+use twilight_model::gateway::{event::Event, CloseFrame, ShardId};
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
-    // Read in our discord bot token, the server we're working in (discord calls them guilds behind the scenes)
-    // and the role we need to assign
-    let token: String = parse_var("DISCORD_TOKEN");
-    let guild: Id<GuildMarker> = parse_var("DISCORD_GUILD");
-    let role: Id<RoleMarker> = parse_var("DISCORD_ROLE");
+    // Read all of our settings in, complaining if any of them are wrong
+    let settings = Settings::from_env();
+    let guild = settings.guild;
 
-    // These values are optional, and they both have default values of 60
-    let message_requirement: u64 = get_var("MESSAGE_REQUIREMENT").unwrap_or(60);
-    let message_cooldown: u64 = get_var("MESSAGE_COOLDOWN").unwrap_or(60);
+    // Figure out what we need discord to send us, based on what features are turned on
+    let (intents, event_types) = ephemerole::gateway_requirements(&settings.config);
+    println!("Connecting with intents {intents:?} for events {event_types:?}");
 
-    // We only care about new server messages, only have one bot instance, and don't care about message content
-    let mut shard = Shard::new(ShardId::ONE, token.clone(), Intents::GUILD_MESSAGES);
+    // We only have one bot instance, so we only need one shard
+    let mut shard = Shard::new(ShardId::ONE, settings.token.clone(), intents);
 
     // Create a new client for telling discord what to do (adding roles)
-    let client = Arc::new(Client::new(token));
+    let client = Arc::new(http_client(&settings));
 
     // Do we need to shut down?
     let shutdown = Arc::new(AtomicBool::new(false));
-    // Makes a copy of shutdown, so we can change it in the shutdown waiter
-    let shutdown_setter = shutdown.clone();
 
-    // Makes a messenger to the shard, so we can tell it to stop
-    let shutdown_sender = shard.sender();
+    // Start a background runtime on a different thread to do non-critical tasks, like
+    // adding roles, and get a way to send new tasks to it. It keeps running until we're done.
+    let background_runtime = start_background_runtime(&settings, &shard, shutdown.clone());
+    let sender_rt_handle = background_runtime.handle.clone();
 
-    // Create a different runtime to do non-critical tasks on a different thread
-    let sender_rt = RuntimeBuilder::new_current_thread()
-        .enable_all()
-        .thread_name("role_adder")
-        .build()
-        .unwrap();
-    // Create a way to send new tasks to this runtime
-    let sender_rt_handle = sender_rt.handle().clone();
-
-    // start a background runtime to handle other I/O tasks, like adding roles
-    std::thread::spawn(move || {
-        // Provide something for the main thread of the background runtime to do
-        // it needs something to do, or we can't spawn on it.
-        sender_rt.block_on(async move {
-            // Wait until we're told to stop
-            shutdown_signal().await;
-            // Set `shutdown` to true, ensuring that every time it is read in the future
-            // it will be true. If the store ordering was not Release, or the load ordering
-            // was not Acquire, this would be a lazy operation
-            shutdown_setter.store(true, Ordering::Release);
-            // Tell discord "hey, disconnect me"
-            shutdown_sender.close(CloseFrame::NORMAL).ok();
-        });
-    });
-
-    // Create a map of users -> current message counts and last message sent time
-    // load_from_file tries to load from the save file if it exists.
-    let mut message_map = MessageMap::new();
-
-    // Store the target server and role, plus the map of user messages, and the discord
-    // notifier all together
-    let config = AssignConfig {
-        role,
-        message_cooldown,
-        message_requirement,
-    };
+    // Everything we know about who's working towards the role. Making room for everyone up front
+    // means the map doesn't have to keep growing when a big server starts talking.
+    let mut bot = Bot::new(
+        settings.config.clone(),
+        settings.expected_users,
+        settings.name_cache_size,
+    );
 
     // create a set of background tasks to handle new messages, so we don't
     // shut them down uncleanly
     let background_tasks = TaskTracker::new();
-    // We only care about new messages
-    let event_types = EventTypeFlags::MESSAGE_CREATE;
+    // Hands out roles in the background. Role additions report back through `finished_grants`
+    // when they're done, so we can put users we couldn't give the role to back in the map.
+    let (mut granter, mut finished_grants) = Granter::new(
+        client.clone(),
+        &settings,
+        background_tasks.clone(),
+        sender_rt_handle.clone(),
+    );
+
+    // Someone can ask us to dump the map to a file while we're running, by sending us SIGUSR1
+    let mut dump_requests = listen_for_dump_requests(&sender_rt_handle);
+    // If we've been asked to, we regularly write how we're doing to a file
+    let mut status = StatusFile::new(settings.status_file.clone(), settings.status_interval);
+    // On busy servers, the leaderboard and stats are worked out every so often instead of every time
+    let mut leaderboard =
+        LeaderboardCache::new(settings.leaderboard_refresh, commands::LEADERBOARD_SIZE);
+
+    // If we've been asked to, count messages from before we started, so people don't start from zero
+    backfill::run(&client, &settings, &mut bot, &mut granter).await;
+
+    // Make sure our commands show up in the server
+    background_tasks.spawn_on(commands::register(client.clone(), guild), &sender_rt_handle);
 
     // while there are more messages, process them
-    while let Some(event) = shard.next_event(event_types).await {
+    loop {
+        // Wait for either a new event from discord, or a role addition to finish
+        let event = tokio::select! {
+            event = shard.next_event(event_types) => event,
+            Some(finished) = finished_grants.recv() => {
+                granter.finished(finished, &bot.config, &mut bot.message_map);
+                continue;
+            }
+            Some(()) = dump_requests.recv() => {
+                spawn_dump(&bot.message_map, &settings.dump_path, &sender_rt_handle);
+                continue;
+            }
+            () = status.tick() => {
+                status.write(leaderboard.get(&bot.message_map, &bot.config).stats, granter.granted());
+                continue;
+            }
+            () = leaderboard.tick() => {
+                leaderboard.refresh(&bot.message_map, &bot.config);
+                continue;
+            }
+        };
+        // If there are no more events, discord is done with us
+        let Some(event) = event else {
+            break;
+        };
         // Failing to receive one message is okay. Log it and go on to the next one.
         let event = match event {
             Ok(event) => event,
@@ -104,92 +132,272 @@ async fn main() {
                 continue;
             }
         };
+        status.observe(&event);
 
-        if matches!(event, Event::GatewayClose(_)) {
-            // The bot automatically reconnects to discord when
-            // improperly disconnected, so we check if we meant to shut down
-            // then exit the loop if we did
-            if shutdown.load(Ordering::Acquire) {
-                break;
+        // The bot automatically reconnects to discord when
+        // improperly disconnected, so we check if we meant to shut down
+        // then exit the loop if we did
+        if matches!(event, Event::GatewayClose(_)) && shutdown.load(Ordering::Acquire) {
+            break;
+        }
+        // If someone used one of our commands, run it and reply in the background
+        if let Event::InteractionCreate(interaction) = &event {
+            let old_config = bot.config.clone();
+            if let Some(reply) = commands::handle(interaction, guild, &mut bot, &leaderboard) {
+                let respond = commands::respond(client.clone(), interaction, reply);
+                background_tasks.spawn_on(respond, &sender_rt_handle);
+            }
+            if opens_up(&old_config, &bot.config) {
+                grant_eligible(&mut bot, &mut granter);
             }
+            continue;
         }
         // If we should add the role, spawn a background task to add the role
-        if let Event::MessageCreate(mc) = event {
-            if ephemerole::should_assign_role(&mc, config, &mut message_map) {
-                let client = client.clone();
-                background_tasks.spawn_on(
-                    add_role(client, guild, config.role, mc.author.id),
-                    &sender_rt_handle,
-                );
-            }
+        if let Some(grant) = bot.process(&event) {
+            warn_unknown_tenure(&event, &bot.config);
+            let name = bot.names.get(grant.user);
+            granter.grant(grant, name, &bot.config, &mut bot.message_map);
         }
     }
-    background_tasks.close();
-    // Wait for all background tasks to complete
-    background_tasks.wait().await;
+    finish_background_tasks(
+        &background_tasks,
+        &mut granter,
+        &mut finished_grants,
+        &mut bot,
+        &settings,
+    )
+    .await;
+    // Nothing is changing any more, so the last status can be completely up to date
+    status.finish(
+        ephemerole::stats(&bot.message_map, &bot.config),
+        granter.granted(),
+    );
     println!("Done, thank you!");
 }
 
-/// Add a role to a specific user, reporting the error in the console
-async fn add_role(
-    client: Arc<Client>,
-    guild: Id<GuildMarker>,
-    role: Id<RoleMarker>,
-    target: Id<UserMarker>,
-) {
-    // Attempt to add the user's role, reporting the error if we can't
-    if let Err(error) = client
-        .add_guild_member_role(guild, target, role)
-        .reason("User hit required message count")
-        .await
-    {
-        eprintln!("ERROR: could not calculate user's message count: {error:?}");
+/// Without knowing when someone joined, they can't be held to the minimum tenure, so say so when
+/// that's how they got the role. It only comes up once for each user, since they aren't tracked after that.
+fn warn_unknown_tenure(event: &Event, config: &AssignConfig) {
+    let Event::MessageCreate(message_create) = event else {
+        return;
+    };
+    if config.min_tenure > 0 && ephemerole::joined_at(message_create).is_none() {
+        let author = message_create.author.id;
+        eprintln!("WARN: don't know when {author} joined, so they got the role without their tenure being checked");
     }
 }
 
-// This function wraps parse_var_res to give human-readable fatal errors
-fn parse_var<T: FromStr>(name: &str) -> T {
-    match parse_var_res(name) {
-        Ok(v) => v,
-        Err(ParseVarError::Parse(_)) => {
-            panic!("Could not parse {name} as {}!", std::any::type_name::<T>())
-        }
-        Err(ParseVarError::Var(VarError::NotPresent)) => {
-            panic!("Could not find {name} in environment!")
-        }
-        Err(ParseVarError::Var(VarError::NotUnicode(_))) => {
-            panic!("{name} does not have a unicode value!")
+/// Could people who've already been counted have earned the role because the config changed?
+/// That's when the requirement (or the streak it takes) goes down, or tracking only gets turned off.
+const fn opens_up(old: &AssignConfig, new: &AssignConfig) -> bool {
+    let lowered = match (old.streak_days, new.streak_days) {
+        (Some(old_days), Some(new_days)) => new_days < old_days,
+        _ => new.message_requirement < old.message_requirement,
+    };
+    lowered || (old.tracking_only && !new.tracking_only)
+}
+
+/// Give the role to everyone who has already earned it under the current config, without waiting for their next message.
+fn grant_eligible(bot: &mut Bot, granter: &mut Granter) {
+    // We only know when someone joined from their messages, so with a minimum tenure
+    // everyone has to wait for their next message to be checked properly
+    if bot.config.min_tenure > 0 {
+        return;
+    }
+    let eligible = bot.take_eligible();
+    println!(
+        "{} users already have enough messages under the new config",
+        eligible.len()
+    );
+    for grant in eligible {
+        let name = bot.names.get(grant.user);
+        granter.grant(grant, name, &bot.config, &mut bot.message_map);
+    }
+}
+
+/// Wait for all background tasks to complete, but don't hang forever if discord isn't answering.
+/// If they take too long, put the users we were giving roles to back in the map. Anyone who didn't
+/// get the role is written out, so they aren't silently lost.
+async fn finish_background_tasks(
+    tasks: &TaskTracker,
+    granter: &mut Granter,
+    finished_grants: &mut UnboundedReceiver<Finished>,
+    bot: &mut Bot,
+    settings: &Settings,
+) {
+    let shutdown_timeout = settings.shutdown_timeout;
+    tasks.close();
+    if timeout(shutdown_timeout, tasks.wait()).await.is_err() {
+        eprintln!(
+            "WARN: {} background tasks still running after {shutdown_timeout:?}, giving up on them",
+            tasks.len()
+        );
+    }
+    // Role additions that did finish have told us how it went, but nobody has listened yet
+    let mut put_back = 0;
+    while let Ok(finished) = finished_grants.try_recv() {
+        if finished.outcome == Outcome::TryAgain {
+            put_back += 1;
         }
+        granter.finished(finished, &bot.config, &mut bot.message_map);
+    }
+    let abandoned = granter.abandon(&bot.config, &mut bot.message_map);
+    if abandoned > 0 {
+        eprintln!("WARN: never finished giving {abandoned} users the role");
+    }
+    // They'd get the role on their next message, but there won't be one now
+    if put_back + abandoned > 0 {
+        dump(&bot.message_map, &settings.dump_path);
+    }
+}
+
+/// Make the client for discord's API. If we've been given a proxy, like twilight's http-proxy
+/// or a fake discord for testing, every request goes there instead.
+fn http_client(settings: &Settings) -> Client {
+    let builder = Client::builder().token(settings.token.clone());
+    match &settings.api_proxy {
+        Some(proxy) => builder.proxy(proxy.clone(), settings.api_proxy_http),
+        None => builder,
     }
+    .build()
 }
 
-// This function wraps parse_var_res to see if the value is invalid (and error if it is) or nonexistent (so we can default it)
-fn get_var<T: FromStr>(name: &str) -> Option<T> {
-    match parse_var_res(name) {
-        Ok(v) => Some(v),
-        Err(ParseVarError::Parse(_)) => {
-            panic!("Could not parse {name} as {}!", std::any::type_name::<T>())
+/// Start listening for requests to dump the map. Each one comes out of the returned receiver.
+/// On platforms without SIGUSR1, nothing ever does.
+fn listen_for_dump_requests(runtime: &Handle) -> UnboundedReceiver<()> {
+    let (dump_sender, dump_requests) = unbounded_channel::<()>();
+    #[cfg(unix)]
+    runtime.spawn(dump_signal(dump_sender));
+    #[cfg(not(unix))]
+    drop(dump_sender);
+    dump_requests
+}
+
+/// Dump the map to a file in the background.
+/// The map is copied first, so we can keep counting messages while it's written out.
+fn spawn_dump(message_map: &MessageMap, path: &Path, runtime: &Handle) {
+    let snapshot = message_map.clone();
+    let path = path.to_owned();
+    runtime.spawn_blocking(move || dump(&snapshot, &path));
+}
+
+/// Dump the map to a file, for debugging, and say where it went
+fn dump(message_map: &MessageMap, path: &Path) {
+    match ephemerole::dump_map(message_map, path) {
+        Ok(()) => println!("Dumped {} users to {}", message_map.len(), path.display()),
+        Err(error) => eprintln!(
+            "ERROR: could not dump users to {}: {error:?}",
+            path.display()
+        ),
+    }
+}
+
+/// Every time we get SIGUSR1, ask the main loop to dump the map to a file
+#[cfg(unix)]
+async fn dump_signal(requests: UnboundedSender<()>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut user_defined =
+        signal(SignalKind::user_defined1()).expect("Failed to listen to sigusr1");
+    while user_defined.recv().await.is_some() {
+        // If the main loop is gone, we're shutting down, so there's nothing to dump
+        if requests.send(()).is_err() {
+            break;
         }
-        Err(ParseVarError::Var(VarError::NotPresent)) => None,
-        Err(ParseVarError::Var(VarError::NotUnicode(_))) => {
-            panic!("{name} does not have a unicode value!")
+    }
+}
+
+/// Start the background runtime on its own thread. Its main task waits until we're told to
+/// stop, then sets `shutdown` and disconnects the shard.
+fn start_background_runtime(
+    settings: &Settings,
+    shard: &Shard,
+    shutdown_setter: Arc<AtomicBool>,
+) -> BackgroundRuntime {
+    // Makes a messenger to the shard, so we can tell it to stop
+    let shutdown_sender = shard.sender();
+
+    BackgroundRuntime::start(settings.grant_threads, async move {
+        // Wait until we're told to stop
+        shutdown_signal().await;
+        // Set `shutdown` to true, ensuring that every time it is read in the future
+        // it will be true. If the store ordering was not Release, or the load ordering
+        // was not Acquire, this would be a lazy operation
+        shutdown_setter.store(true, Ordering::Release);
+        // Tell discord "hey, disconnect me"
+        shutdown_sender.close(CloseFrame::NORMAL).ok();
+    })
+}
+
+/// The runtime background tasks run on, on its own thread.
+///
+/// It keeps running until this is dropped, even after we've been told to shut down, so role
+/// additions that are still going can finish while the main loop waits for them.
+struct BackgroundRuntime {
+    /// A way to send new tasks to it
+    handle: Handle,
+    /// Tells it we're done with it
+    done: Option<oneshot::Sender<()>>,
+    /// The thread it runs on
+    thread: Option<JoinHandle<()>>,
+}
+
+impl BackgroundRuntime {
+    /// Start the runtime with `threads` threads, running `main_task` on it
+    fn start(threads: usize, main_task: impl Future<Output = ()> + Send + 'static) -> Self {
+        let runtime = background_runtime(threads);
+        let handle = runtime.handle().clone();
+        let (done, mut finished) = oneshot::channel();
+        let thread = std::thread::spawn(move || {
+            // Provide something for the main thread of the background runtime to do
+            // it needs something to do, or we can't spawn on it.
+            runtime.block_on(async move {
+                #[allow(clippy::redundant_pub_crate)]
+                // This shuts off a warning that we can't avoid
+                {
+                    tokio::select! {
+                        () = main_task => {},
+                        _ = &mut finished => return,
+                    }
+                }
+                // Keep running until we're done with it. When this returns, the runtime is
+                // dropped, which cancels every task that's still running on it.
+                finished.await.ok();
+            });
+        });
+        Self {
+            handle,
+            done: Some(done),
+            thread: Some(thread),
         }
     }
 }
 
-// The bot uses "environment variables" for configuration.
-// This helps the bot pick one of them out and convert the value (which is always text) into a number.
-fn parse_var_res<T: FromStr>(name: &str) -> Result<T, ParseVarError<T>> {
-    std::env::var(name) // get the variable
-        .map_err(ParseVarError::Var)? // if it doesn't exist, convert the error to a ParseVarError and bail out
-        .parse() // Try to turn it into the type we want
-        .map_err(ParseVarError::Parse) // If it can't be turned into that, wrap up the error and return it
+impl Drop for BackgroundRuntime {
+    fn drop(&mut self) {
+        if let Some(done) = self.done.take() {
+            done.send(()).ok();
+        }
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
 }
 
-/// The different types of errors we can get when we try to parse a variable
-enum ParseVarError<T: FromStr> {
-    Var(VarError),
-    Parse(<T as FromStr>::Err),
+/// Build the runtime that background tasks, like adding roles, run on. One thread is plenty
+/// for most servers, but a big backlog of role additions gets through faster with more.
+fn background_runtime(threads: usize) -> Runtime {
+    let mut builder = if threads > 1 {
+        let mut builder = RuntimeBuilder::new_multi_thread();
+        builder.worker_threads(threads);
+        builder
+    } else {
+        RuntimeBuilder::new_current_thread()
+    };
+    builder
+        .enable_all()
+        .thread_name("role_adder")
+        .build()
+        .unwrap()
 }
 
 /// Windows and Linux supported code to return from this function when this app is told to shut down.
@@ -220,3 +428,119 @@ async fn shutdown_signal() {
         .await
         .expect("Failed to listen to ctrl+c");
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use ephemerole::Grant;
+    use twilight_model::id::Id;
+
+    use super::*;
+    use crate::fake_discord::{fake_discord, silent_discord};
+
+    #[test]
+    fn background_runtime_flavors() {
+        use tokio::runtime::RuntimeFlavor;
+
+        for threads in [0, 1] {
+            let runtime = background_runtime(threads);
+            assert_eq!(
+                runtime.handle().runtime_flavor(),
+                RuntimeFlavor::CurrentThread
+            );
+        }
+        let runtime = background_runtime(4);
+        assert_eq!(
+            runtime.handle().runtime_flavor(),
+            RuntimeFlavor::MultiThread
+        );
+        assert_eq!(runtime.metrics().num_workers(), 4);
+    }
+
+    #[tokio::test]
+    async fn background_tasks_keep_running_after_shutdown_signal() {
+        // The shutdown signal has already come, like it has by the time the main loop ends
+        let background = BackgroundRuntime::start(1, async {});
+        let tasks = TaskTracker::new();
+        tasks.spawn_on(
+            tokio::time::sleep(Duration::from_mins(1)),
+            &background.handle,
+        );
+        tasks.close();
+        assert!(timeout(Duration::from_millis(100), tasks.wait())
+            .await
+            .is_err());
+        // Once we're done with the runtime, whatever's left is cancelled
+        drop(background);
+        assert!(timeout(Duration::from_secs(5), tasks.wait()).await.is_ok());
+    }
+
+    /// Start giving user 5 the role through a fake discord at `address`, then shut down straight away.
+    /// Returns the granter and bot once shutting down is done, plus the dump if one was written.
+    async fn shut_down_while_granting(
+        address: String,
+        name: &str,
+    ) -> (Granter, Bot, Option<String>) {
+        let mut settings = Settings::for_tests(address);
+        settings.shutdown_timeout = Duration::from_millis(200);
+        settings.dump_path = std::env::temp_dir().join(format!(
+            "ephemerole-test-{name}-{}.json",
+            std::process::id()
+        ));
+        let background = BackgroundRuntime::start(1, async {});
+        let tasks = TaskTracker::new();
+        let client = Arc::new(http_client(&settings));
+        let (mut granter, mut finished_grants) =
+            Granter::new(client, &settings, tasks.clone(), background.handle.clone());
+        let mut bot = Bot::new(settings.config.clone(), 0, 0);
+        let grant = Grant {
+            user: Id::new(5),
+            sent_at: 1000,
+            first_seen_at: 0,
+            messages: 60,
+        };
+        granter.grant(grant, None, &bot.config, &mut bot.message_map);
+
+        finish_background_tasks(
+            &tasks,
+            &mut granter,
+            &mut finished_grants,
+            &mut bot,
+            &settings,
+        )
+        .await;
+        let dump = std::fs::read_to_string(&settings.dump_path).ok();
+        std::fs::remove_file(&settings.dump_path).ok();
+        (granter, bot, dump)
+    }
+
+    #[tokio::test]
+    async fn slow_role_additions_are_abandoned_and_dumped() {
+        let (_, bot, dump) = shut_down_while_granting(silent_discord(), "slow").await;
+        // They never got the role, so they're back in the map, and written out so we know about them
+        assert!(bot.message_map[&Id::new(5)].has_earned(&bot.config));
+        assert!(dump.unwrap().contains("\"5\""));
+    }
+
+    #[tokio::test]
+    async fn failed_role_additions_are_put_back_and_dumped() {
+        let (address, _requests) = fake_discord(
+            "500 Internal Server Error",
+            r#"{"message": "500: Internal Server Error", "code": 0}"#,
+        );
+        let (_, bot, dump) = shut_down_while_granting(address, "failed").await;
+        assert!(bot.message_map[&Id::new(5)].has_earned(&bot.config));
+        assert!(dump.unwrap().contains("\"5\""));
+    }
+
+    #[tokio::test]
+    async fn finished_role_additions_are_counted() {
+        let (address, _requests) = fake_discord("204 No Content", "");
+        let (granter, bot, dump) = shut_down_while_granting(address, "finished").await;
+        assert_eq!(granter.granted(), 1);
+        assert!(bot.message_map.is_empty());
+        // Nobody was left out, so there's nothing to write
+        assert_eq!(dump, None);
+    }
+}
@@ -0,0 +1,151 @@
+use std::collections::BTreeMap;
+
+use ahash::AHashMap;
+use twilight_model::{
+    id::{marker::UserMarker, Id},
+    user::User,
+};
+
+/// A small cache of usernames, so logs and messages can say who someone is without asking discord.
+///
+/// Every message comes with its author, so we learn names for free. When the cache is full,
+/// the user we heard from least recently is forgotten.
+#[derive(Debug, Clone)]
+pub struct NameCache {
+    /// How many names we remember at most
+    capacity: usize,
+    /// Each user's name, plus the tick we last heard from them at
+    names: AHashMap<Id<UserMarker>, (String, u64)>,
+    /// Users ordered by when we last heard from them, oldest first
+    recency: BTreeMap<u64, Id<UserMarker>>,
+    /// Counts up every time we hear from someone, so we know who was most recent
+    tick: u64,
+}
+
+impl NameCache {
+    /// Make an empty cache that remembers at most `capacity` names
+    #[must_use]
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            names: AHashMap::with_capacity(capacity),
+            recency: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    /// Remember the name of someone we just heard from
+    pub fn insert(&mut self, user: &User) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some((_, last_seen)) = self.names.get_mut(&user.id) {
+            // We already know them, so just move them to the back of the line.
+            // Names hardly ever change, and they get refreshed when they fall out of the cache.
+            self.recency.remove(last_seen);
+            *last_seen = tick;
+        } else {
+            // Make room for them by forgetting whoever we heard from longest ago
+            if self.names.len() >= self.capacity {
+                if let Some((_, oldest)) = self.recency.pop_first() {
+                    self.names.remove(&oldest);
+                }
+            }
+            self.names.insert(user.id, (display_name(user), tick));
+        }
+        self.recency.insert(tick, user.id);
+    }
+
+    /// Get the name of a user, if we remember it
+    #[must_use]
+    pub fn get(&self, user: Id<UserMarker>) -> Option<&str> {
+        self.names.get(&user).map(|(name, _)| name.as_str())
+    }
+
+    /// How many names we remember right now
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    /// Do we remember anybody's name?
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+}
+
+/// The name people see in discord. Users who haven't moved to the new username system
+/// still have a discriminator, like `name#1234`.
+#[must_use]
+pub fn display_name(user: &User) -> String {
+    match &user.global_name {
+        Some(global_name) => global_name.clone(),
+        None if user.discriminator == 0 => user.name.clone(),
+        None => format!("{}#{}", user.name, user.discriminator()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A user with this ID and username
+    fn user(id: u64, name: &str) -> User {
+        User {
+            avatar_decoration_data: None,
+            accent_color: None,
+            avatar: None,
+            avatar_decoration: None,
+            banner: None,
+            bot: false,
+            discriminator: 0,
+            email: None,
+            flags: None,
+            global_name: None,
+            id: Id::new(id),
+            locale: None,
+            mfa_enabled: None,
+            name: name.to_owned(),
+            premium_type: None,
+            public_flags: None,
+            system: None,
+            verified: None,
+        }
+    }
+
+    #[test]
+    fn forgets_least_recently_seen() {
+        let mut names = NameCache::new(2);
+        names.insert(&user(1, "one"));
+        names.insert(&user(2, "two"));
+        // Hearing from 1 again makes 2 the oldest
+        names.insert(&user(1, "one"));
+        names.insert(&user(3, "three"));
+        assert_eq!(names.len(), 2);
+        assert_eq!(names.get(Id::new(1)), Some("one"));
+        assert_eq!(names.get(Id::new(2)), None);
+        assert_eq!(names.get(Id::new(3)), Some("three"));
+    }
+
+    #[test]
+    fn zero_capacity_remembers_nobody() {
+        let mut names = NameCache::new(0);
+        names.insert(&user(1, "one"));
+        assert!(names.is_empty());
+        assert_eq!(names.get(Id::new(1)), None);
+    }
+
+    #[test]
+    fn display_names() {
+        let mut legacy = user(1, "old");
+        legacy.discriminator = 42;
+        assert_eq!(display_name(&legacy), "old#0042");
+        assert_eq!(display_name(&user(2, "new")), "new");
+        let mut global = user(3, "new");
+        global.global_name = Some("Shown".to_owned());
+        assert_eq!(display_name(&global), "Shown");
+    }
+}
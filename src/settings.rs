@@ -0,0 +1,215 @@
+//! Reading the bot's settings from environment variables
+
+use std::{path::PathBuf, time::Duration};
+
+use ephemerole::{get_list_var, get_var, parse_var, profile, AssignConfig, GrantLimiter};
+use twilight_model::id::{
+    marker::{ChannelMarker, GuildMarker},
+    Id,
+};
+
+/// Everything the bot is set up with when it starts
+pub struct Settings {
+    /// Our discord bot token
+    pub token: String,
+    /// Where to send API requests instead of discord, if anywhere
+    pub api_proxy: Option<String>,
+    /// Should we talk to the proxy over plain HTTP, instead of HTTPS?
+    pub api_proxy_http: bool,
+    /// The server we're working in (discord calls them guilds behind the scenes)
+    pub guild: Id<GuildMarker>,
+    /// What it takes to get the role. Staff can change this while the bot is running with /configure.
+    pub config: AssignConfig,
+    /// How many role additions can be waiting at once before we stop accepting more
+    pub grant_queue_capacity: usize,
+    /// The most roles we'll give out in a window of time, if there's a limit
+    pub grant_limiter: Option<GrantLimiter>,
+    /// How long to wait for role additions to finish when shutting down
+    pub shutdown_timeout: Duration,
+    /// How many threads to add roles on
+    pub grant_threads: usize,
+    /// What to say in the audit log when we give someone the role
+    pub grant_reason: String,
+    /// If this is set, we privately message users this when they get the role
+    pub dm_template: Option<String>,
+    /// How many usernames to remember for logs and messages
+    pub name_cache_size: usize,
+    /// How many users we expect to be tracking at once, so we can make room for them up front
+    pub expected_users: usize,
+    /// Where to dump the map when we get SIGUSR1
+    pub dump_path: PathBuf,
+    /// Where to write how the bot is doing, if anywhere
+    pub status_file: Option<PathBuf>,
+    /// How often to write the status file
+    pub status_interval: Duration,
+    /// How often to work out the leaderboard and stats again, if we keep a copy of them at all
+    pub leaderboard_refresh: Option<Duration>,
+    /// Should we count messages from before the bot started?
+    pub backfill: bool,
+    /// Which channels to count old messages from
+    pub backfill_channels: Vec<Id<ChannelMarker>>,
+    /// How many old messages to count from each channel
+    pub backfill_limit: usize,
+}
+
+impl Settings {
+    /// Read all of our settings from the environment, stopping the bot with a
+    /// human-readable error if any of them are wrong.
+    pub fn from_env() -> Self {
+        // Running more than one copy of the bot on one machine, like for testing, is easier with profiles
+        let profile = profile();
+        if let Some(profile) = &profile {
+            println!(
+                "Using profile {profile}, so {}_ variables take priority",
+                profile.to_uppercase()
+            );
+        }
+
+        // Read in our discord bot token, and the server we're working in
+        let token: String = parse_var("DISCORD_TOKEN");
+        let guild: Id<GuildMarker> = parse_var("DISCORD_GUILD");
+
+        // What it takes to get the role
+        let config = AssignConfig::from_env();
+        if config.tracking_only {
+            println!("Only tracking messages, so nobody gets the role until tracking only is turned off with /configure");
+        }
+
+        // Complain about config that's probably a mistake. If we're being strict, refuse to start.
+        let strict_config: bool = get_var("STRICT_CONFIG").unwrap_or(false);
+        for warning in ephemerole::validate_config(&config) {
+            assert!(
+                !strict_config,
+                "Refusing to start with STRICT_CONFIG: {warning}"
+            );
+            eprintln!("WARN: {warning}");
+        }
+
+        // Writing the status file constantly would be useless, and tokio can't wait for no time at all
+        let status_interval: u64 = get_var("STATUS_INTERVAL").unwrap_or(60);
+        assert!(
+            status_interval > 0,
+            "STATUS_INTERVAL must be at least 1 second"
+        );
+
+        // Same for working out the leaderboard. Leaving it unset is how to not keep a copy at all.
+        let leaderboard_refresh: Option<u64> = get_var("LEADERBOARD_REFRESH");
+        assert!(
+            leaderboard_refresh != Some(0),
+            "LEADERBOARD_REFRESH must be at least 1 second, or unset to not keep a copy of the leaderboard"
+        );
+        // With no room at all, nobody could ever get the role
+        let grant_queue_capacity: usize = get_var("GRANT_QUEUE_CAPACITY").unwrap_or(1000);
+        assert!(
+            grant_queue_capacity > 0,
+            "GRANT_QUEUE_CAPACITY must be at least 1"
+        );
+        let dump_path: Option<PathBuf> = get_var("DUMP_PATH");
+        let dump_path_set = dump_path.is_some();
+
+        let settings = Self {
+            token,
+            api_proxy: get_var("DISCORD_API_PROXY"),
+            api_proxy_http: get_var("DISCORD_API_PROXY_HTTP").unwrap_or(false),
+            guild,
+            config,
+            grant_queue_capacity,
+            grant_limiter: get_var("MAX_GRANTS_PER_WINDOW")
+                .map(|max| GrantLimiter::new(get_var("GRANT_WINDOW").unwrap_or(60), max)),
+            grant_threads: get_var("GRANT_THREADS").unwrap_or(1),
+            shutdown_timeout: Duration::from_secs(get_var("SHUTDOWN_TIMEOUT").unwrap_or(30)),
+            grant_reason: get_var("GRANT_REASON")
+                .unwrap_or_else(|| "User hit required message count".to_owned()),
+            dm_template: get_var("DM_ON_GRANT"),
+            name_cache_size: get_var("NAME_CACHE_SIZE").unwrap_or(10_000),
+            expected_users: get_var("EXPECTED_USERS").unwrap_or(0),
+            // Each profile gets its own dump by default, so they don't overwrite each other
+            dump_path: dump_path.unwrap_or_else(|| default_dump_path(profile.as_deref())),
+            status_file: get_var("STATUS_FILE"),
+            status_interval: Duration::from_secs(status_interval),
+            leaderboard_refresh: leaderboard_refresh.map(Duration::from_secs),
+            backfill: get_var("BACKFILL").unwrap_or(false),
+            backfill_channels: get_list_var("BACKFILL_CHANNELS"),
+            backfill_limit: get_var("BACKFILL_LIMIT").unwrap_or(1000),
+        };
+        settings.check_output_paths(
+            dump_path_set,
+            get_var("CREATE_OUTPUT_DIRS").unwrap_or(false),
+        );
+        settings
+    }
+
+    /// Make sure we can write every file we might write later, so a bad path stops the bot now,
+    /// instead of failing quietly hours later.
+    ///
+    /// The dump is only for debugging, so if nobody asked for it somewhere in particular,
+    /// not being able to write it is just a warning.
+    fn check_output_paths(&self, dump_path_set: bool, create_dirs: bool) {
+        let paths = std::iter::once(("DUMP_PATH", &self.dump_path, dump_path_set)).chain(
+            self.status_file
+                .iter()
+                .map(|path| ("STATUS_FILE", path, true)),
+        );
+        for (name, path, required) in paths {
+            match ephemerole::check_writable(path, create_dirs) {
+                Ok(()) => {}
+                Err(error) if required => panic!("Can't write {name} {}: {error}", path.display()),
+                Err(error) => eprintln!("WARN: Can't write {name} {}: {error}", path.display()),
+            }
+        }
+    }
+}
+
+/// Where the dump goes if `DUMP_PATH` isn't set. Each profile gets its own, so they don't overwrite each other.
+fn default_dump_path(profile: Option<&str>) -> PathBuf {
+    profile.map_or_else(
+        || "ephemerole-dump.json".into(),
+        |profile| format!("ephemerole-{profile}-dump.json").into(),
+    )
+}
+
+#[cfg(test)]
+impl Settings {
+    /// The bot's default settings, talking to a fake discord at `api_proxy` over plain HTTP
+    pub fn for_tests(api_proxy: String) -> Self {
+        Self {
+            token: "token".to_owned(),
+            api_proxy: Some(api_proxy),
+            api_proxy_http: true,
+            guild: Id::new(1),
+            config: AssignConfig::new(Id::new(2)),
+            grant_queue_capacity: 1000,
+            grant_limiter: None,
+            shutdown_timeout: Duration::from_secs(30),
+            grant_threads: 1,
+            grant_reason: "User hit required message count".to_owned(),
+            dm_template: None,
+            name_cache_size: 0,
+            expected_users: 0,
+            dump_path: PathBuf::from("ephemerole-dump.json"),
+            status_file: None,
+            status_interval: Duration::from_mins(1),
+            leaderboard_refresh: None,
+            backfill: false,
+            backfill_channels: Vec::new(),
+            backfill_limit: 1000,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_dump_path_is_per_profile() {
+        assert_eq!(
+            default_dump_path(None),
+            PathBuf::from("ephemerole-dump.json")
+        );
+        assert_eq!(
+            default_dump_path(Some("staging")),
+            PathBuf::from("ephemerole-staging-dump.json")
+        );
+    }
+}
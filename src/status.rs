@@ -0,0 +1,79 @@
+//! Writing a small status file, so scripts can check on the bot without reading its logs
+
+use std::{
+    future::pending,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use ephemerole::{MapStats, Status};
+use tokio::time::{interval, Interval};
+use twilight_model::gateway::event::Event;
+
+/// Keeps track of what goes in the status file, and when to write it
+pub struct StatusFile {
+    /// Where to write it. If there's nowhere, we never write anything.
+    path: Option<PathBuf>,
+    /// Ticks whenever it's time to write the file again. There's only one if there's a path.
+    interval: Option<Interval>,
+    /// When we started
+    started: Instant,
+    /// Are we connected to discord right now?
+    connected: bool,
+}
+
+impl StatusFile {
+    /// Get ready to write the status to `path` every `every`, if there is a path
+    pub fn new(path: Option<PathBuf>, every: Duration) -> Self {
+        Self {
+            // Without a path there's nothing to write, so don't wait for anything
+            interval: path.as_ref().map(|_| interval(every)),
+            path,
+            started: Instant::now(),
+            connected: false,
+        }
+    }
+
+    /// Wait until it's time to write the file again. Without a path, this never finishes.
+    pub async fn tick(&mut self) {
+        match &mut self.interval {
+            Some(interval) => {
+                interval.tick().await;
+            }
+            None => pending().await,
+        }
+    }
+
+    /// Keep track of whether we're connected, from the events discord sends us.
+    /// Anything other than the connection closing means we're connected.
+    pub const fn observe(&mut self, event: &Event) {
+        self.connected = !matches!(event, Event::GatewayClose(_));
+    }
+
+    /// Write the status one last time when we're shutting down, so it doesn't look like we're still connected
+    pub fn finish(mut self, map_stats: MapStats, grants_this_session: u64) {
+        self.connected = false;
+        self.write(map_stats, grants_this_session);
+    }
+
+    /// Write the status file, if we have one, replacing the old one
+    pub fn write(&self, map_stats: MapStats, grants_this_session: u64) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let status = Status {
+            uptime_seconds: self.started.elapsed().as_secs(),
+            gateway_connected: self.connected,
+            grants_this_session,
+            stats: map_stats,
+        };
+        let result =
+            ephemerole::write_atomic(path, |writer| ephemerole::export_status(&status, writer));
+        if let Err(error) = result {
+            eprintln!(
+                "ERROR: could not write status to {}: {error:?}",
+                path.display()
+            );
+        }
+    }
+}